@@ -0,0 +1,464 @@
+use std::{collections::HashMap, fmt::Display, time::{Duration, SystemTime, UNIX_EPOCH}};
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+// Failures allowed before backoff starts
+pub const FREE_ATTEMPTS: u32 = 3;
+pub const SOURCE_FREE_ATTEMPTS: u32 = 10;
+// Backoff doubles with every failure past the free ones, up to this
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+pub const LOGIN_LOCKOUT_AFTER: u32 = 5;
+// A connection may try many logins, so it gets a higher limit
+pub const SOURCE_LOCKOUT_AFTER: u32 = 20;
+pub const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+// Counters are forgotten after this long without failures
+pub const RESET_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockoutTarget {
+    Login,
+    Source
+}
+
+#[derive(Debug, Clone)]
+pub struct LockoutEvent {
+    pub target: LockoutTarget,
+    // Login or connection name
+    pub key: String,
+    pub time: SystemTime,
+    pub until: SystemTime
+}
+
+impl Display for LockoutEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let target = match self.target {
+            LockoutTarget::Login => "login",
+            LockoutTarget::Source => "connection",
+        };
+        write!(f, "{} {} locked at {} until {}", target, self.key,
+            DateTime::<Local>::from(self.time).format("%Y-%m-%d %H:%M:%S"),
+            DateTime::<Local>::from(self.until).format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AttemptCounter {
+    // Failures before backoff starts
+    free: u32,
+    failures: u32,
+    last_failure: SystemTime,
+    locked_until: Option<SystemTime>
+}
+
+impl AttemptCounter {
+    fn new(free: u32, now: SystemTime) -> AttemptCounter {
+        AttemptCounter {
+            free,
+            failures: 0,
+            last_failure: now,
+            locked_until: None
+        }
+    }
+
+    fn is_stale(&self, now: SystemTime) -> bool {
+        let locked = self.locked_until.is_some_and(|until| now < until);
+        !locked && now.duration_since(self.last_failure).unwrap_or_default() >= RESET_AFTER
+    }
+
+    // Earliest time the next attempt is allowed
+    fn next_attempt(&self) -> SystemTime {
+        if let Some(until) = self.locked_until {
+            return until;
+        }
+        if self.failures < self.free {
+            return self.last_failure;
+        }
+        let backoff = Duration::from_secs(1 << (self.failures - self.free).min(16));
+        self.last_failure + backoff.min(MAX_BACKOFF)
+    }
+}
+
+// Failed login counters per login and per connection
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    by_login: HashMap<String, AttemptCounter>,
+    by_source: HashMap<String, AttemptCounter>,
+    events: Vec<LockoutEvent>
+}
+
+impl LoginThrottle {
+    pub fn new() -> LoginThrottle {
+        LoginThrottle::default()
+    }
+
+    // Whether an attempt is allowed now, checked before the password
+    pub fn allows(&mut self, login: &str, source: &str, now: SystemTime) -> bool {
+        Self::allows_in(&mut self.by_login, login, now) && Self::allows_in(&mut self.by_source, source, now)
+    }
+
+    fn allows_in(counters: &mut HashMap<String, AttemptCounter>, key: &str, now: SystemTime) -> bool {
+        match counters.get(key) {
+            Some(counter) if counter.is_stale(now) => {
+                counters.remove(key);
+                true
+            }
+            Some(counter) => now >= counter.next_attempt(),
+            None => true,
+        }
+    }
+
+    pub fn record_failure(&mut self, login: &str, source: &str, now: SystemTime) {
+        let login_event = Self::fail_in(&mut self.by_login, LockoutTarget::Login, login, now);
+        let source_event = Self::fail_in(&mut self.by_source, LockoutTarget::Source, source, now);
+        self.events.extend(login_event.into_iter().chain(source_event));
+    }
+
+    // Returns an event when this failure locks the key
+    fn fail_in(counters: &mut HashMap<String, AttemptCounter>, target: LockoutTarget, key: &str, now: SystemTime) -> Option<LockoutEvent> {
+        let (free, limit) = match target {
+            LockoutTarget::Login => (FREE_ATTEMPTS, LOGIN_LOCKOUT_AFTER),
+            LockoutTarget::Source => (SOURCE_FREE_ATTEMPTS, SOURCE_LOCKOUT_AFTER),
+        };
+        let counter = counters.entry(key.to_string()).or_insert_with(|| AttemptCounter::new(free, now));
+        if counter.locked_until.is_some_and(|until| now >= until) {
+            // Lockout is over, start counting again
+            *counter = AttemptCounter::new(free, now);
+        }
+        counter.failures += 1;
+        counter.last_failure = now;
+        if counter.failures >= limit && counter.locked_until.is_none() {
+            let until = now + LOCKOUT_DURATION;
+            counter.locked_until = Some(until);
+            return Some(LockoutEvent {
+                target,
+                key: key.to_string(),
+                time: now,
+                until
+            });
+        }
+        None
+    }
+
+    // The connection keeps its count, so one good account can't reset it
+    pub fn record_success(&mut self, login: &str) {
+        self.by_login.remove(login);
+    }
+
+    pub fn is_locked(&self, login: &str, now: SystemTime) -> bool {
+        self.by_login.get(login).and_then(|c| c.locked_until).is_some_and(|until| now < until)
+    }
+
+    pub fn unlock(&mut self, login: &str) -> bool {
+        self.by_login.remove(login).is_some()
+    }
+
+    pub fn events(&self) -> &Vec<LockoutEvent> {
+        &self.events
+    }
+}
+
+// RFC 6238 defaults, which authenticator apps expect
+pub const TOTP_STEP: u64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+// Steps of clock drift accepted on each side
+pub const TOTP_WINDOW: u64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+pub const RECOVERY_CODE_LENGTH: usize = 10;
+
+// RFC 4226 HOTP with HMAC-SHA1, TOTP counts time steps with it
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    code % 10u32.pow(digits)
+}
+
+// RFC 4648 alphabet without padding, as used in otpauth URIs
+pub fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data.iter() {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            out.push(ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct Totp {
+    secret: Vec<u8>
+}
+
+impl Totp {
+    // 160 bit secret, as recommended for SHA1
+    pub fn generate() -> Totp {
+        let secret = (0..20).map(|_| rand::thread_rng().gen()).collect();
+        Totp { secret }
+    }
+
+    pub fn from_secret(secret: Vec<u8>) -> Totp {
+        Totp { secret }
+    }
+
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    pub fn step_at(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / TOTP_STEP
+    }
+
+    pub fn code_at(&self, time: SystemTime) -> String {
+        self.code_for_step(Self::step_at(time))
+    }
+
+    fn code_for_step(&self, step: u64) -> String {
+        format!("{:0width$}", hotp(&self.secret, step, TOTP_DIGITS), width = TOTP_DIGITS as usize)
+    }
+
+    // Step the code belongs to, within the drift window.
+    // Only the exact zero padded digits count, no signs or dropped zeros.
+    pub fn matching_step(&self, code: &str, time: SystemTime) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {return None;}
+        let now = Self::step_at(time);
+        (now.saturating_sub(TOTP_WINDOW)..=now + TOTP_WINDOW)
+            .find(|step| self.code_for_step(*step) == code)
+    }
+
+    pub fn uri(&self, issuer: &str, account: &str) -> String {
+        format!("otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}",
+            base32_encode(&self.secret))
+    }
+}
+
+// Second factor of an account, active once a first code was confirmed
+#[derive(Debug, Clone)]
+pub struct TwoFactor {
+    totp: Totp,
+    pub confirmed: bool,
+    recovery_codes: Vec<String>,
+    // Codes can't be used twice
+    last_step: Option<u64>
+}
+
+impl TwoFactor {
+    pub fn new(totp: Totp) -> TwoFactor {
+        TwoFactor {
+            totp,
+            confirmed: false,
+            recovery_codes: vec![],
+            last_step: None
+        }
+    }
+
+    pub fn totp(&self) -> &Totp {
+        &self.totp
+    }
+
+    pub fn verify_code(&mut self, code: &str, time: SystemTime) -> bool {
+        match self.totp.matching_step(code, time) {
+            Some(step) if self.last_step.is_none_or(|last| step > last) => {
+                self.last_step = Some(step);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Replaces any previous codes, each can be used once
+    pub fn new_recovery_codes(&mut self) -> Vec<String> {
+        self.recovery_codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| rand::thread_rng().sample_iter(&Alphanumeric).take(RECOVERY_CODE_LENGTH).map(char::from).collect())
+            .collect();
+        self.recovery_codes.clone()
+    }
+
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let len = self.recovery_codes.len();
+        self.recovery_codes.retain(|c| c != code.trim());
+        len != self.recovery_codes.len()
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_codes.len()
+    }
+}
+
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+pub const SESSION_TOKEN_LENGTH: usize = 43;
+
+// Only hashes are kept, so a leaked session list can't be replayed
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Login that can be resumed with its token instead of the password
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: u64,
+    token_hash: String,
+    pub user_id: u64,
+    // Connection that opened it
+    pub source: String,
+    pub created: SystemTime,
+    pub last_used: SystemTime,
+    pub expires: SystemTime
+}
+
+impl Session {
+    // Returns the session and the token, which is not stored anywhere
+    pub fn new(id: u64, user_id: u64, source: &str) -> (Session, String) {
+        let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(SESSION_TOKEN_LENGTH).map(char::from).collect();
+        let now = SystemTime::now();
+        let session = Session {
+            id,
+            token_hash: hash_token(&token),
+            user_id,
+            source: source.to_string(),
+            created: now,
+            last_used: now,
+            expires: now + SESSION_LIFETIME
+        };
+        (session, token)
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        self.token_hash == hash_token(token.trim())
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.expires
+    }
+}
+
+impl Display for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. from {}, last used {}, expires {}", self.id, self.source,
+            DateTime::<Local>::from(self.last_used).format("%Y-%m-%d %H:%M"),
+            DateTime::<Local>::from(self.expires).format("%Y-%m-%d %H:%M"))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn check_backoff() {
+        let mut throttle = LoginThrottle::new();
+        let start = SystemTime::now();
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(throttle.allows("test", "console", start));
+            throttle.record_failure("test", "console", start);
+        }
+        // Past the free attempts the wait doubles
+        assert!(!throttle.allows("test", "console", start));
+        assert!(throttle.allows("test", "console", start + Duration::from_secs(1)));
+        throttle.record_failure("test", "console", start + Duration::from_secs(1));
+        assert!(!throttle.allows("test", "console", start + Duration::from_secs(2)));
+        assert!(throttle.allows("test", "console", start + Duration::from_secs(3)));
+        // Other logins from elsewhere are not affected
+        assert!(throttle.allows("other", "remote", start));
+        throttle.record_success("test");
+        assert!(throttle.allows("test", "console", start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn check_lockout() {
+        let mut throttle = LoginThrottle::new();
+        let start = SystemTime::now();
+        for i in 0..LOGIN_LOCKOUT_AFTER {
+            throttle.record_failure("test", "console", start + MAX_BACKOFF * i);
+        }
+        let last = start + MAX_BACKOFF * (LOGIN_LOCKOUT_AFTER - 1);
+        assert!(throttle.is_locked("test", last));
+        assert!(!throttle.allows("test", "console", last + MAX_BACKOFF));
+        assert_eq!(1, throttle.events().len());
+        assert_eq!(LockoutTarget::Login, throttle.events()[0].target);
+        assert!(throttle.allows("test", "console", last + LOCKOUT_DURATION));
+
+        // Spraying many logins from one connection locks the connection
+        for i in 0..SOURCE_LOCKOUT_AFTER {
+            throttle.record_failure(&format!("user{i}"), "remote", start);
+        }
+        assert!(!throttle.allows("fresh", "remote", start));
+        assert_eq!(LockoutTarget::Source, throttle.events()[1].target);
+    }
+
+    #[test]
+    fn check_totp_vectors() {
+        // RFC 6238 appendix B, SHA1 with 8 digits
+        let secret = b"12345678901234567890";
+        let vectors = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(code, hotp(secret, time / TOTP_STEP, 8));
+        }
+        let totp = Totp::from_secret(secret.to_vec());
+        assert_eq!("287082", totp.code_at(UNIX_EPOCH + Duration::from_secs(59)));
+        let at = UNIX_EPOCH + Duration::from_secs(59);
+        assert!(totp.matching_step("287082", at).is_some());
+        for code in ["+287082", "0287082", "2870820", "28708", "-287082", "287 082"] {
+            assert!(totp.matching_step(code, at).is_none());
+        }
+        // Leading zeros can't be dropped either
+        let at = (0..).map(|i| UNIX_EPOCH + Duration::from_secs(i * TOTP_STEP))
+            .find(|t| totp.code_at(*t).starts_with('0')).unwrap();
+        let code = totp.code_at(at);
+        assert!(totp.matching_step(&code, at).is_some());
+        assert!(totp.matching_step(&code[1..], at).is_none());
+        assert!(totp.matching_step(&format!("+{}", &code[1..]), at).is_none());
+        assert_eq!("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", base32_encode(secret));
+    }
+
+    #[test]
+    fn check_two_factor() {
+        let mut two_factor = TwoFactor::new(Totp::generate());
+        let now = SystemTime::now();
+        let code = two_factor.totp().code_at(now);
+        let drifted = two_factor.totp().code_at(now + Duration::from_secs(TOTP_STEP));
+        assert!(two_factor.verify_code(&code, now));
+        // Same code again is a replay
+        assert!(!two_factor.verify_code(&code, now));
+        assert!(two_factor.verify_code(&drifted, now));
+        assert!(!two_factor.verify_code("abc", now));
+
+        let codes = two_factor.new_recovery_codes();
+        assert_eq!(RECOVERY_CODE_COUNT, codes.len());
+        assert!(two_factor.use_recovery_code(&codes[0]));
+        assert!(!two_factor.use_recovery_code(&codes[0]));
+        assert_eq!(RECOVERY_CODE_COUNT - 1, two_factor.recovery_codes_left());
+    }
+
+    #[test]
+    fn check_session_tokens() {
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", hash_token(""));
+        let (session, token) = Session::new(0, 3, "console");
+        assert_eq!(SESSION_TOKEN_LENGTH, token.len());
+        assert!(session.matches(&token));
+        assert!(!session.matches(&token[1..]));
+        assert!(!format!("{session:?}").contains(&token));
+        assert!(session.is_expired(session.created + SESSION_LIFETIME));
+    }
+}
@@ -0,0 +1,781 @@
+use std::time::Duration;
+use crate::Simulation;
+use crate::place::{roles::{Permissions, RolePerms}, OverrideTarget, PlaceSort, Visibility};
+use crate::user::{Presence, ProfileField};
+
+// Text commands typed into the menu, e.g. "/role grant 3 2"
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Roles,
+    RoleCreate(String),
+    RoleRename(u64, String),
+    RolePerms(u64, RolePermsArgs),
+    RoleDelete(u64),
+    RoleGrant(u64, u64),
+    RoleRevoke(u64, u64),
+    PlaceTransfer(u64, u64),
+    PlaceDelete(u64),
+    PlaceVisibility(u64, Visibility),
+    Invites,
+    // Max uses and minutes, None means no limit
+    InviteCreate(Option<u32>, Option<u64>),
+    InviteRevoke(String),
+    Join(String),
+    Requests,
+    MyRequests,
+    // Request id and optional note for the requester
+    RequestApprove(u64, Option<String>),
+    RequestReject(u64, Option<String>),
+    // Sort order and name search
+    Places(PlaceSort, Option<String>),
+    Settings,
+    SetSetting(PlaceSetting, String),
+    // None shows the current user's profile
+    Profile(Option<String>),
+    // Empty value clears the field
+    ProfileSet(ProfileField, String),
+    Friends,
+    FriendAdd(u64),
+    FriendAccept(u64),
+    FriendDecline(u64),
+    FriendCancel(u64),
+    FriendRemove(u64),
+    SetPresence(Presence),
+    // User id, minutes (None lasts until lifted) and reason
+    Suspend(u64, Option<u64>, String),
+    Unsuspend(u64),
+    Operator(u64, bool),
+    TwoFactorEnroll,
+    TwoFactorConfirm(String),
+    TwoFactorDisable(String),
+    TwoFactorRecovery(String),
+    Email(String),
+    Verify(String),
+    VerifyResend,
+    Sessions,
+    // None revokes all sessions
+    SessionRevoke(Option<u64>),
+    Lockouts,
+    Unlock(String),
+    Blocked,
+    Block(u64),
+    Unblock(u64),
+    Joined,
+    // Positions in the joined list, counted from 1
+    GoTo(usize),
+    Favourite(usize, bool),
+    MoveJoined(usize, usize),
+    // None leaves the current place
+    Leave(Option<u64>),
+    Channels,
+    // Channel id or name
+    ChannelSwitch(String),
+    ChannelCreate(String),
+    ChannelDelete(u64),
+    ChannelTopic(u64, String),
+    // Channel, role or user, allow, deny
+    ChannelPerms(u64, OverrideTarget, Permissions, Permissions),
+}
+
+// Fields of PlaceSettings that can be set with "/set"
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PlaceSetting {
+    Description,
+    Topic,
+    Rules,
+    Welcome,
+    DefaultRole,
+    MaxLength
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RolePermsArgs {
+    pub allow: Permissions,
+    pub deny: Permissions,
+    pub priority: u8
+}
+
+pub fn is_command(input: &str) -> bool {
+    input.trim_start().starts_with('/')
+}
+
+pub fn parse(input: &str) -> Result<Command, &'static str> {
+    let input = input.trim();
+    let input = input.strip_prefix('/').ok_or("Commands start with '/'")?;
+    let mut words = input.split_whitespace();
+    match words.next() {
+        Some("roles") => Ok(Command::Roles),
+        Some("role") => parse_role(words.collect()),
+        Some("place") => parse_place(words.collect()),
+        Some("invites") => Ok(Command::Invites),
+        Some("invite") => parse_invite(words.collect()),
+        Some("places") => parse_places(words.collect()),
+        Some("channels") => Ok(Command::Channels),
+        Some("channel") => parse_channel(words.collect()),
+        Some("profile") => parse_profile(words.collect()),
+        Some("friends") => Ok(Command::Friends),
+        Some("friend") => parse_friend(words.collect()),
+        Some("presence") => match words.next() {
+            Some("online") => Ok(Command::SetPresence(Presence::Online)),
+            Some("dnd") => Ok(Command::SetPresence(Presence::DoNotDisturb)),
+            _ => Err("Usage: /presence online|dnd"),
+        },
+        Some("suspend") => parse_suspend(words.collect()),
+        Some("unsuspend") => match words.next() {
+            Some(id) => Ok(Command::Unsuspend(parse_id(id)?)),
+            None => Err("Usage: /unsuspend <user id>"),
+        },
+        Some(op @ ("op" | "deop")) => match words.next() {
+            Some(id) => Ok(Command::Operator(parse_id(id)?, op == "op")),
+            None => Err("Usage: /op|deop <user id>"),
+        },
+        Some("2fa") => match (words.next(), words.next()) {
+            (Some("enroll"), None) => Ok(Command::TwoFactorEnroll),
+            (Some("confirm"), Some(code)) => Ok(Command::TwoFactorConfirm(code.to_string())),
+            (Some("disable"), Some(code)) => Ok(Command::TwoFactorDisable(code.to_string())),
+            (Some("recovery"), Some(code)) => Ok(Command::TwoFactorRecovery(code.to_string())),
+            _ => Err("Usage: /2fa enroll | /2fa confirm|disable|recovery <code>"),
+        },
+        Some("email") => match words.next() {
+            Some(email) => Ok(Command::Email(email.to_string())),
+            None => Err("Usage: /email <address>"),
+        },
+        Some("verify") => match words.next() {
+            Some("resend") => Ok(Command::VerifyResend),
+            Some(token) => Ok(Command::Verify(token.to_string())),
+            None => Err("Usage: /verify <token> | /verify resend"),
+        },
+        Some("sessions") => Ok(Command::Sessions),
+        Some("session") => match (words.next(), words.next()) {
+            (Some("revoke"), Some("all")) => Ok(Command::SessionRevoke(None)),
+            (Some("revoke"), Some(id)) => Ok(Command::SessionRevoke(Some(parse_id(id)?))),
+            _ => Err("Usage: /session revoke <id>|all"),
+        },
+        Some("lockouts") => Ok(Command::Lockouts),
+        Some("unlock") => match words.next() {
+            Some(login) => Ok(Command::Unlock(login.to_string())),
+            None => Err("Usage: /unlock <login>"),
+        },
+        Some("blocked") => Ok(Command::Blocked),
+        Some("block") => match words.next() {
+            Some(id) => Ok(Command::Block(parse_id(id)?)),
+            None => Err("Usage: /block <user id>"),
+        },
+        Some("unblock") => match words.next() {
+            Some(id) => Ok(Command::Unblock(parse_id(id)?)),
+            None => Err("Usage: /unblock <user id>"),
+        },
+        Some("joined") => Ok(Command::Joined),
+        Some("go") => match words.next() {
+            Some(n) => Ok(Command::GoTo(parse_position(n)?)),
+            None => Err("Usage: /go <n>"),
+        },
+        Some(fav @ ("fav" | "unfav")) => match words.next() {
+            Some(n) => Ok(Command::Favourite(parse_position(n)?, fav == "fav")),
+            None => Err("Usage: /fav|unfav <n>"),
+        },
+        Some("move") => match (words.next(), words.next()) {
+            (Some(from), Some(to)) => Ok(Command::MoveJoined(parse_position(from)?, parse_position(to)?)),
+            _ => Err("Usage: /move <from> <to>"),
+        },
+        Some("leave") => match words.next() {
+            Some(id) => Ok(Command::Leave(Some(parse_id(id)?))),
+            None => Ok(Command::Leave(None)),
+        },
+        Some("settings") => Ok(Command::Settings),
+        Some("set") => parse_set(words.collect()),
+        Some("requests") => Ok(Command::Requests),
+        Some("myrequests") => Ok(Command::MyRequests),
+        Some("request") => parse_request(words.collect()),
+        Some("join") => match words.next() {
+            Some(code) => Ok(Command::Join(code.to_string())),
+            None => Err("Usage: /join <code>"),
+        },
+        _ => Err("Unknown command"),
+    }
+}
+
+fn parse_role(args: Vec<&str>) -> Result<Command, &'static str> {
+    match args.as_slice() {
+        ["create", name @ ..] if !name.is_empty() => Ok(Command::RoleCreate(name.join(" "))),
+        ["rename", id, name @ ..] if !name.is_empty() => Ok(Command::RoleRename(parse_id(id)?, name.join(" "))),
+        ["perms", id, priority, perms @ ..] => {
+            let priority = priority.parse().map_err(|_| "Priority should be a number from 0 to 255")?;
+            let (allow, deny) = parse_perm_list(perms)?;
+            Ok(Command::RolePerms(parse_id(id)?, RolePermsArgs {allow, deny, priority}))
+        }
+        ["delete", id] => Ok(Command::RoleDelete(parse_id(id)?)),
+        ["grant", user, role] => Ok(Command::RoleGrant(parse_id(user)?, parse_id(role)?)),
+        ["revoke", user, role] => Ok(Command::RoleRevoke(parse_id(user)?, parse_id(role)?)),
+        _ => Err("Usage: /role create|rename|perms|delete|grant|revoke ..."),
+    }
+}
+
+fn parse_place(args: Vec<&str>) -> Result<Command, &'static str> {
+    match args.as_slice() {
+        ["transfer", place, user] => Ok(Command::PlaceTransfer(parse_id(place)?, parse_id(user)?)),
+        ["delete", place] => Ok(Command::PlaceDelete(parse_id(place)?)),
+        ["visibility", place, visibility] => {
+            let visibility = match *visibility {
+                "public" => Visibility::Public,
+                "invite" => Visibility::InviteOnly,
+                "request" => Visibility::RequestToJoin,
+                "hidden" => Visibility::Hidden,
+                _ => {return Err("Visibility should be public, invite, request or hidden");}
+            };
+            Ok(Command::PlaceVisibility(parse_id(place)?, visibility))
+        }
+        _ => Err("Usage: /place transfer <place> <user> | delete <place> | visibility <place> public|invite|request|hidden"),
+    }
+}
+
+fn parse_invite(args: Vec<&str>) -> Result<Command, &'static str> {
+    // "-" or nothing means no limit
+    fn limit<T: std::str::FromStr>(arg: Option<&&str>) -> Result<Option<T>, &'static str> {
+        match arg {
+            None | Some(&"-") => Ok(None),
+            Some(x) => x.parse().map(Some).map_err(|_| "Limit should be a number or '-'"),
+        }
+    }
+    match args.as_slice() {
+        ["create", rest @ ..] if rest.len() <= 2 => Ok(Command::InviteCreate(limit(rest.first())?, limit(rest.get(1))?)),
+        ["revoke", code] => Ok(Command::InviteRevoke(code.to_string())),
+        _ => Err("Usage: /invite create [max uses] [minutes] | /invite revoke <code>"),
+    }
+}
+
+// "/places [id|name|members|activity] [search...]"
+fn parse_places(args: Vec<&str>) -> Result<Command, &'static str> {
+    let (sort, rest) = match args.split_first() {
+        Some((&"id", rest)) => (PlaceSort::Id, rest),
+        Some((&"name", rest)) => (PlaceSort::Name, rest),
+        Some((&"members", rest)) => (PlaceSort::Members, rest),
+        Some((&"activity", rest)) => (PlaceSort::Activity, rest),
+        _ => (PlaceSort::Id, args.as_slice()),
+    };
+    let search = if rest.is_empty() {None} else {Some(rest.join(" "))};
+    Ok(Command::Places(sort, search))
+}
+
+fn parse_channel(args: Vec<&str>) -> Result<Command, &'static str> {
+    match args.as_slice() {
+        ["switch", channel] => Ok(Command::ChannelSwitch(channel.to_string())),
+        ["create", name] => Ok(Command::ChannelCreate(name.to_string())),
+        ["delete", id] => Ok(Command::ChannelDelete(parse_id(id)?)),
+        ["topic", id, topic @ ..] => Ok(Command::ChannelTopic(parse_id(id)?, topic.join(" "))),
+        ["perms", channel, kind, id, perms @ ..] => {
+            let target = match *kind {
+                "role" => OverrideTarget::Role(parse_id(id)?),
+                "user" => OverrideTarget::User(parse_id(id)?),
+                _ => {return Err("Overrides are for a role or a user");}
+            };
+            let (allow, deny) = parse_perm_list(perms)?;
+            Ok(Command::ChannelPerms(parse_id(channel)?, target, allow, deny))
+        }
+        _ => Err("Usage: /channel switch <id|name> | create <name> | delete <id> | topic <id> <topic> | perms <channel> role|user <id> [+perm -perm]"),
+    }
+}
+
+fn parse_set(args: Vec<&str>) -> Result<Command, &'static str> {
+    let (field, value) = args.split_first().ok_or("Usage: /set description|topic|rules|welcome|defaultrole|maxlength <value>")?;
+    let field = match *field {
+        "description" => PlaceSetting::Description,
+        "topic" => PlaceSetting::Topic,
+        "rules" => PlaceSetting::Rules,
+        "welcome" => PlaceSetting::Welcome,
+        "defaultrole" => PlaceSetting::DefaultRole,
+        "maxlength" => PlaceSetting::MaxLength,
+        _ => {return Err("Unknown setting");}
+    };
+    Ok(Command::SetSetting(field, value.join(" ")))
+}
+
+fn parse_profile(args: Vec<&str>) -> Result<Command, &'static str> {
+    match args.as_slice() {
+        [] => Ok(Command::Profile(None)),
+        ["set", field, value @ ..] => {
+            let field = match *field {
+                "name" => ProfileField::DisplayName,
+                "bio" => ProfileField::Bio,
+                "pronouns" => ProfileField::Pronouns,
+                "status" => ProfileField::Status,
+                _ => {return Err("Usage: /profile set name|bio|pronouns|status [value]");}
+            };
+            Ok(Command::ProfileSet(field, value.join(" ")))
+        }
+        ["set"] => Err("Usage: /profile set name|bio|pronouns|status [value]"),
+        user => Ok(Command::Profile(Some(user.join(" ")))),
+    }
+}
+
+fn parse_friend(args: Vec<&str>) -> Result<Command, &'static str> {
+    match args.as_slice() {
+        ["add", id] => Ok(Command::FriendAdd(parse_id(id)?)),
+        ["accept", id] => Ok(Command::FriendAccept(parse_id(id)?)),
+        ["decline", id] => Ok(Command::FriendDecline(parse_id(id)?)),
+        ["cancel", id] => Ok(Command::FriendCancel(parse_id(id)?)),
+        ["remove", id] => Ok(Command::FriendRemove(parse_id(id)?)),
+        _ => Err("Usage: /friend add|accept|decline|cancel|remove <user id>"),
+    }
+}
+
+fn parse_suspend(args: Vec<&str>) -> Result<Command, &'static str> {
+    match args.as_slice() {
+        [id, minutes, reason @ ..] if !reason.is_empty() => {
+            let minutes = match *minutes {
+                "-" => None,
+                m => Some(m.parse().map_err(|_| "Minutes should be a number or -")?),
+            };
+            Ok(Command::Suspend(parse_id(id)?, minutes, reason.join(" ")))
+        }
+        _ => Err("Usage: /suspend <user id> <minutes|-> <reason>"),
+    }
+}
+
+fn parse_request(args: Vec<&str>) -> Result<Command, &'static str> {
+    let response = |rest: &[&str]| if rest.is_empty() {None} else {Some(rest.join(" "))};
+    match args.as_slice() {
+        ["approve", id, rest @ ..] => Ok(Command::RequestApprove(parse_id(id)?, response(rest))),
+        ["reject", id, rest @ ..] => Ok(Command::RequestReject(parse_id(id)?, response(rest))),
+        _ => Err("Usage: /request approve|reject <id> [message]"),
+    }
+}
+
+fn parse_id(input: &str) -> Result<u64, &'static str> {
+    input.parse().map_err(|_| "Id should be a number")
+}
+
+fn parse_position(input: &str) -> Result<usize, &'static str> {
+    input.parse().map_err(|_| "Position should be a number")
+}
+
+// "+send_messages -ban" -> (allow, deny)
+pub fn parse_perm_list(words: &[&str]) -> Result<(Permissions, Permissions), &'static str> {
+    let mut allow = Permissions::empty();
+    let mut deny = Permissions::empty();
+    for word in words.iter() {
+        let (target, name) = if let Some(name) = word.strip_prefix('+') {
+            (&mut allow, name)
+        } else if let Some(name) = word.strip_prefix('-') {
+            (&mut deny, name)
+        } else {
+            return Err("Permissions should start with '+' or '-'");
+        };
+        *target |= Permissions::from_name(&name.to_uppercase()).ok_or("Unknown permission")?;
+    }
+    Ok((allow, deny))
+}
+
+pub fn execute(sim: &mut Simulation, command: Command) -> Result<String, &'static str> {
+    if sim.password_change_required() {return Err(crate::PASSWORD_CHANGE_REQUIRED);}
+    match command {
+        Command::Roles => {
+            let place = sim.return_current_place();
+            let place = place.borrow();
+            let mut out = format!("Role list of {}", place.name);
+            for role in place.return_role_vec().iter() {
+                out.push_str(&format!("\n{}. {}", role.id(), role));
+            }
+            Ok(out)
+        }
+        Command::RoleCreate(name) => {
+            let id = sim.create_role(name, RolePerms::new_basic())?;
+            Ok(format!("Role created with id {id}"))
+        }
+        Command::RoleRename(id, name) => {
+            sim.rename_role(id, name)?;
+            Ok("Role renamed".to_string())
+        }
+        Command::RolePerms(id, args) => {
+            sim.edit_role_perms(id, RolePerms::new(args.allow, args.deny, args.priority))?;
+            Ok("Role permissions updated".to_string())
+        }
+        Command::RoleDelete(id) => {
+            sim.delete_role(id)?;
+            Ok("Role deleted".to_string())
+        }
+        Command::RoleGrant(user, role) => {
+            sim.grant_role(user, role)?;
+            Ok("Role granted".to_string())
+        }
+        Command::RoleRevoke(user, role) => {
+            sim.revoke_role(user, role)?;
+            Ok("Role revoked".to_string())
+        }
+        Command::PlaceTransfer(place, user) => {
+            sim.transfer_ownership(place, user)?;
+            Ok("Ownership transferred".to_string())
+        }
+        Command::PlaceDelete(place) => {
+            sim.delete_place(place)?;
+            Ok("Place deleted".to_string())
+        }
+        Command::PlaceVisibility(place, visibility) => {
+            sim.set_place_visibility(place, visibility)?;
+            Ok("Visibility changed".to_string())
+        }
+        Command::Invites => {
+            let invites = sim.return_invites()?;
+            let mut out = format!("Invites of {}", sim.return_current_place().borrow().name);
+            for invite in invites.iter() {
+                out.push_str(&format!("\n{invite}"));
+            }
+            Ok(out)
+        }
+        Command::InviteCreate(max_uses, minutes) => {
+            let code = sim.create_invite(max_uses, minutes.map(|m| Duration::from_secs(m * 60)))?;
+            Ok(format!("Invite code: {code}"))
+        }
+        Command::InviteRevoke(code) => {
+            sim.revoke_invite(&code)?;
+            Ok("Invite revoked".to_string())
+        }
+        Command::Join(code) => {
+            let place = sim.join_with_invite(&code)?;
+            Ok(format!("Joined place {place}"))
+        }
+        Command::Places(sort, search) => {
+            let mut out = String::from("Places");
+            for listing in sim.place_directory(search.as_deref(), sort).iter() {
+                out.push_str(&format!("\n{listing}"));
+            }
+            Ok(out)
+        }
+        Command::Channels => {
+            let place = sim.return_current_place();
+            let mut out = format!("Channels of {}", place.borrow().name);
+            for channel in sim.return_visible_channels().iter() {
+                out.push_str(&format!("\n{channel}"));
+            }
+            Ok(out)
+        }
+        Command::ChannelSwitch(channel) => {
+            let id = match channel.parse() {
+                Ok(id) => id,
+                Err(_) => {
+                    let place = sim.return_current_place();
+                    let x = place.borrow().find_channel_by_name(&channel).map(|c| c.id()).ok_or("Can't find the channel")?;
+                    x
+                }
+            };
+            sim.switch_channel(id)?;
+            Ok("Channel switched".to_string())
+        }
+        Command::ChannelCreate(name) => {
+            let id = sim.create_channel(name)?;
+            Ok(format!("Channel created with id {id}"))
+        }
+        Command::ChannelDelete(id) => {
+            sim.delete_channel(id)?;
+            Ok("Channel deleted".to_string())
+        }
+        Command::ChannelTopic(id, topic) => {
+            sim.set_channel_topic(id, topic)?;
+            Ok("Topic changed".to_string())
+        }
+        Command::ChannelPerms(channel, target, allow, deny) => {
+            sim.set_channel_override(channel, target, allow, deny)?;
+            Ok("Channel permissions updated".to_string())
+        }
+        Command::Profile(query) => {
+            let user = match query {
+                Some(query) => sim.find_user(&query)?,
+                None => sim.return_current_user().ok_or("You need to log in first")?,
+            };
+            let user = user.borrow();
+            Ok(format!("{} (id {}, login {})\n{}", user.name(), user.id(), user.login(), user.profile()))
+        }
+        Command::ProfileSet(field, value) => {
+            sim.edit_profile(field, &value)?;
+            Ok("Profile updated".to_string())
+        }
+        Command::Friends => {
+            let mut out = String::from("Your friends");
+            for friend in sim.return_friends().iter() {
+                out.push_str(&format!("\n{friend}"));
+            }
+            let (incoming, outgoing) = sim.return_friend_requests();
+            let name = |id: u64| sim.get_user_by_id(id).map(|u| u.borrow().name()).unwrap_or(String::from("?"));
+            for request in incoming.iter() {
+                out.push_str(&format!("\nRequest from {} ({})", name(request.from), request.from));
+            }
+            for request in outgoing.iter() {
+                out.push_str(&format!("\nRequest sent to {} ({})", name(request.to), request.to));
+            }
+            Ok(out)
+        }
+        Command::FriendAdd(id) => Ok(sim.send_friend_request(id)?.to_string()),
+        Command::FriendAccept(id) => {
+            sim.accept_friend_request(id)?;
+            Ok("Friend request accepted".to_string())
+        }
+        Command::FriendDecline(id) => {
+            sim.decline_friend_request(id)?;
+            Ok("Friend request declined".to_string())
+        }
+        Command::FriendCancel(id) => {
+            sim.cancel_friend_request(id)?;
+            Ok("Friend request cancelled".to_string())
+        }
+        Command::FriendRemove(id) => {
+            sim.remove_friend(id)?;
+            Ok("Friend removed".to_string())
+        }
+        Command::SetPresence(presence) => {
+            sim.set_presence(presence)?;
+            Ok(format!("You are now {presence}"))
+        }
+        Command::Suspend(id, minutes, reason) => {
+            sim.suspend_user(id, reason, minutes.map(|m| Duration::from_secs(m * 60)))?;
+            Ok("User suspended".to_string())
+        }
+        Command::Unsuspend(id) => {
+            sim.unsuspend_user(id)?;
+            Ok("Suspension lifted".to_string())
+        }
+        Command::Operator(id, operator) => {
+            sim.set_operator(id, operator)?;
+            Ok(if operator {"User is now an operator"} else {"User is no longer an operator"}.to_string())
+        }
+        Command::TwoFactorEnroll => {
+            let uri = sim.begin_totp_enrollment()?;
+            Ok(format!("Add this to your authenticator app, then /2fa confirm <code>\n{uri}"))
+        }
+        Command::TwoFactorConfirm(code) => {
+            let codes = sim.confirm_totp_enrollment(&code)?;
+            Ok(format!("Two factor login is on. Keep these recovery codes safe, each works once:\n{}", codes.join("\n")))
+        }
+        Command::TwoFactorDisable(code) => {
+            sim.disable_two_factor(&code)?;
+            Ok("Two factor login is off".to_string())
+        }
+        Command::TwoFactorRecovery(code) => {
+            let codes = sim.regenerate_recovery_codes(&code)?;
+            Ok(format!("New recovery codes, the old ones no longer work:\n{}", codes.join("\n")))
+        }
+        Command::Email(email) => {
+            sim.set_email(&email)?;
+            Ok("E-mail changed, check your inbox for a verification token".to_string())
+        }
+        Command::Verify(token) => {
+            sim.verify_email(&token)?;
+            Ok("E-mail verified".to_string())
+        }
+        Command::VerifyResend => {
+            sim.resend_verification()?;
+            Ok("Verification mail sent".to_string())
+        }
+        Command::Sessions => {
+            let current = sim.current_session_id();
+            let mut out = String::from("Your sessions");
+            for session in sim.return_sessions().iter() {
+                let marker = if Some(session.id) == current {" (this one)"} else {""};
+                out.push_str(&format!("\n{session}{marker}"));
+            }
+            Ok(out)
+        }
+        Command::SessionRevoke(Some(id)) => {
+            sim.revoke_session(id)?;
+            Ok("Session revoked".to_string())
+        }
+        Command::SessionRevoke(None) => {
+            sim.revoke_all_sessions()?;
+            Ok("All sessions revoked".to_string())
+        }
+        Command::Lockouts => {
+            let mut out = String::from("Lockouts");
+            for event in sim.return_lockout_events()?.iter() {
+                out.push_str(&format!("\n{event}"));
+            }
+            Ok(out)
+        }
+        Command::Unlock(login) => {
+            sim.unlock_login(&login)?;
+            Ok("Login unlocked".to_string())
+        }
+        Command::Blocked => {
+            let user = sim.return_current_user().ok_or("You need to log in first")?;
+            let mut out = String::from("Blocked users");
+            for id in user.borrow().data.blocked().iter() {
+                let name = sim.get_user_by_id(*id).map(|u| u.borrow().name()).unwrap_or(String::from("?"));
+                out.push_str(&format!("\n{name} ({id})"));
+            }
+            Ok(out)
+        }
+        Command::Block(id) => {
+            sim.block_user(id)?;
+            Ok("User blocked".to_string())
+        }
+        Command::Unblock(id) => {
+            sim.unblock_user(id)?;
+            Ok("User unblocked".to_string())
+        }
+        Command::Joined => {
+            let mut out = String::from("Your places");
+            for (i, (joined, name)) in sim.return_joined_places().iter().enumerate() {
+                let star = if joined.favourite {"*"} else {" "};
+                out.push_str(&format!("\n{}.{star} {name} (id {})", i + 1, joined.place_id));
+            }
+            Ok(out)
+        }
+        Command::GoTo(n) => {
+            let place = sim.switch_to_joined_place(n)?;
+            Ok(format!("Switched to place {place}"))
+        }
+        Command::Favourite(n, favourite) => {
+            sim.set_favourite_place(n, favourite)?;
+            Ok(if favourite {"Marked as favourite"} else {"Removed from favourites"}.to_string())
+        }
+        Command::MoveJoined(from, to) => {
+            sim.move_joined_place(from, to)?;
+            Ok("Place moved".to_string())
+        }
+        Command::Leave(place) => {
+            let place = place.unwrap_or_else(|| sim.return_current_place().borrow().id());
+            sim.leave_place(place)?;
+            Ok("You left the place".to_string())
+        }
+        Command::Settings => {
+            let place = sim.return_current_place();
+            let place = place.borrow();
+            Ok(format!("Settings of {}\n{}", place.name, place.settings()))
+        }
+        Command::SetSetting(field, value) => {
+            let place = sim.return_current_place();
+            let mut settings = place.borrow().settings().clone();
+            let place_id = place.borrow().id();
+            match field {
+                PlaceSetting::Description => settings.description = value,
+                PlaceSetting::Topic => settings.topic = value,
+                PlaceSetting::Rules => settings.rules = value,
+                PlaceSetting::Welcome => settings.welcome_message = value,
+                PlaceSetting::DefaultRole => settings.default_role = parse_id(&value)?,
+                PlaceSetting::MaxLength => settings.max_message_length = value.parse().map_err(|_| "Length should be a number")?,
+            }
+            sim.update_place_settings(place_id, settings)?;
+            Ok("Settings updated".to_string())
+        }
+        Command::Requests => {
+            let mut out = String::from("Pending join requests");
+            for request in sim.return_pending_join_requests()?.iter() {
+                let name = match sim.get_user_by_id(request.user_id) {
+                    Ok(user) => user.borrow().name(),
+                    Err(_) => String::from("?"),
+                };
+                out.push_str(&format!("\n{request} [{name}]"));
+            }
+            Ok(out)
+        }
+        Command::MyRequests => {
+            let mut out = String::from("Your join requests");
+            for (id, name, request) in sim.return_my_join_requests().iter() {
+                out.push_str(&format!("\n{name} ({id}): {request}"));
+            }
+            Ok(out)
+        }
+        Command::RequestApprove(id, response) => {
+            sim.approve_join_request(id, response)?;
+            Ok("Request approved".to_string())
+        }
+        Command::RequestReject(id, response) => {
+            sim.reject_join_request(id, response)?;
+            Ok("Request rejected".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn check_parse_role() {
+        assert_eq!(Ok(Command::RoleCreate("Old Guard".to_string())), parse("/role create Old Guard"));
+        assert_eq!(Ok(Command::RoleGrant(3, 2)), parse("/role grant 3 2"));
+        assert_eq!(
+            Ok(Command::RolePerms(2, RolePermsArgs {
+                allow: Permissions::PIN,
+                deny: Permissions::SEND_MESSAGES,
+                priority: 10
+            })),
+            parse("/role perms 2 10 +pin -send_messages")
+        );
+        assert!(parse("/role perms 2 10 pin").is_err());
+        assert!(parse("/role grant x 2").is_err());
+        assert!(parse("role create x").is_err());
+        assert_eq!(Ok(Command::PlaceTransfer(1, 4)), parse("/place transfer 1 4"));
+    }
+
+    #[test]
+    fn check_parse_invite() {
+        assert_eq!(Ok(Command::InviteCreate(None, None)), parse("/invite create"));
+        assert_eq!(Ok(Command::InviteCreate(Some(5), Some(60))), parse("/invite create 5 60"));
+        assert_eq!(Ok(Command::InviteCreate(None, Some(60))), parse("/invite create - 60"));
+        assert_eq!(Ok(Command::Join("AbC123".to_string())), parse("/join AbC123"));
+        assert_eq!(Ok(Command::PlaceVisibility(2, Visibility::Hidden)), parse("/place visibility 2 hidden"));
+        assert!(parse("/invite create x").is_err());
+    }
+
+    #[test]
+    fn check_parse_request() {
+        assert_eq!(Ok(Command::RequestReject(3, Some("Not now".to_string()))), parse("/request reject 3 Not now"));
+        assert_eq!(Ok(Command::RequestApprove(3, None)), parse("/request approve 3"));
+    }
+
+    #[test]
+    fn check_parse_places() {
+        assert_eq!(Ok(Command::Places(PlaceSort::Members, Some("rust fans".to_string()))), parse("/places members rust fans"));
+        assert_eq!(Ok(Command::Places(PlaceSort::Id, Some("rust".to_string()))), parse("/places rust"));
+    }
+
+    #[test]
+    fn check_parse_set() {
+        assert_eq!(Ok(Command::SetSetting(PlaceSetting::Topic, "Cats and dogs".to_string())), parse("/set topic Cats and dogs"));
+        assert!(parse("/set colour red").is_err());
+    }
+
+    #[test]
+    fn check_parse_leave() {
+        assert_eq!(Ok(Command::Leave(Some(3))), parse("/leave 3"));
+        assert_eq!(Ok(Command::Leave(None)), parse("/leave"));
+    }
+
+    #[test]
+    fn check_parse_profile() {
+        assert_eq!(Ok(Command::Profile(Some("The Forgotten One".to_string()))), parse("/profile The Forgotten One"));
+        assert_eq!(Ok(Command::ProfileSet(ProfileField::Status, String::new())), parse("/profile set status"));
+        assert!(parse("/profile set age 12").is_err());
+    }
+
+    #[test]
+    fn check_parse_friend() {
+        assert_eq!(Ok(Command::FriendAccept(4)), parse("/friend accept 4"));
+        assert!(parse("/friend poke 4").is_err());
+    }
+
+    #[test]
+    fn check_parse_suspend() {
+        assert_eq!(Ok(Command::Suspend(4, None, "spam links".to_string())), parse("/suspend 4 - spam links"));
+        assert_eq!(Ok(Command::Suspend(4, Some(30), "flood".to_string())), parse("/suspend 4 30 flood"));
+        assert!(parse("/suspend 4 30").is_err());
+    }
+
+    #[test]
+    fn check_parse_session() {
+        assert_eq!(Ok(Command::SessionRevoke(None)), parse("/session revoke all"));
+        assert_eq!(Ok(Command::SessionRevoke(Some(2))), parse("/session revoke 2"));
+    }
+
+    #[test]
+    fn check_parse_channel() {
+        assert_eq!(Ok(Command::ChannelSwitch("news".to_string())), parse("/channel switch news"));
+        assert_eq!(
+            Ok(Command::ChannelPerms(1, OverrideTarget::Role(0), Permissions::empty(), Permissions::SEND_MESSAGES)),
+            parse("/channel perms 1 role 0 -send_messages")
+        );
+        assert_eq!(
+            Ok(Command::ChannelPerms(1, OverrideTarget::User(4), Permissions::READ_HISTORY, Permissions::empty())),
+            parse("/channel perms 1 user 4 +read_history")
+        );
+    }
+}
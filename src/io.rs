@@ -1,4 +1,4 @@
-use std::io;
+use std::io::{self, IsTerminal};
 use crate::user::User;
 use super::place::Place;
 
@@ -6,6 +6,18 @@ use super::place::Place;
 pub struct LoginData(String, String);
 
 impl LoginData {
+    pub fn new(login: String, password: String) -> LoginData {
+        LoginData(login, password)
+    }
+
+    // Registration entries, the password typed twice
+    pub fn from_registration(login: &str, password: String, repeated: &str) -> Result<LoginData, &'static str> {
+        if login.trim().is_empty() {return Err("Login cannot be empty");}
+        if password.is_empty() {return Err("Password cannot be empty");}
+        if password != repeated {return Err("Passwords don't match, try again.");}
+        Ok(LoginData(String::from(login.trim()), password))
+    }
+
     pub fn login(&self) -> String {
             self.0.clone()
     }
@@ -15,38 +27,101 @@ impl LoginData {
     }
 }
 
+// Registration asks for the password twice until both entries match
 pub fn get_name_and_pass_from_console(repeat_password: bool) -> LoginData
-    // Write error handling
+{
+    loop {
+        let login = ask("Please enter login and password:\nLogin: ");
+        let pass = read_password("Password: ");
+        if !repeat_password {
+            return LoginData(login, pass);
+        }
+        match LoginData::from_registration(&login, pass, &read_password("Repeat password: ")) {
+            Ok(data) => return data,
+            Err(err) => println!("{err}"),
+        }
+    }
+}
+
+// Not echoed on a terminal. Piped input comes from stdin,
+// rpassword would read the terminal instead.
+pub fn read_password(prompt: &str) -> String {
+    if !io::stdin().is_terminal() {return ask(prompt);}
+    match rpassword::prompt_password(format!("{prompt}\n")) {
+        Ok(pass) => pass,
+        Err(_) => ask(prompt),
+    }
+}
+
+// New password typed twice, for changes and resets
+pub fn get_new_password_from_console() -> String {
+    loop {
+        let pass = read_password("New password:");
+        if pass == read_password("Repeat new password:") {
+            return pass;
+        }
+        println!("Passwords don't match, try again.");
+    }
+}
+
+pub struct PlaceCreationData(String, String);
+
+impl PlaceCreationData {
+    pub fn name(&self) -> String {
+        self.0.clone()
+    }
+
+    pub fn description(&self) -> String {
+        self.1.clone()
+    }
+}
+
+pub fn get_place_creation_data_from_console() -> PlaceCreationData
 {
     let mut input = String::new();
-    let login;
-    let pass;
-    println!("Please enter login and password:\nLogin: ");
+    println!("Please enter place creation data:\nPlace name: ");
     io::stdin().read_line(&mut input).expect("Wrong data input");
-    login = String::from(input.trim());
+    let name = String::from(input.trim());
     input.clear();
-    println!("Password: ");
+    println!("Description: ");
     io::stdin().read_line(&mut input).expect("Wrong data input");
-    pass = String::from(input.trim());
-    input.clear();
-    if repeat_password {
-        println!("Repeat password: ");
-        io::stdin().read_line(&mut input).expect("Wrong data input");
-    }
-    LoginData(login, pass)
+    PlaceCreationData(name, String::from(input.trim()))
 }
 
-pub fn get_place_creation_data_from_console() -> String 
-    // After adding more stuff to place struct and shit add them here too
-{
+// Print a prompt and read one trimmed line
+pub fn ask(prompt: &str) -> String {
     let mut input = String::new();
-    let name;
-    println!("Please enter place creation data:\nPlace name: ");
+    println!("{prompt}");
     io::stdin().read_line(&mut input).expect("Wrong data input");
-    name = String::from(input.trim());
-    name
+    String::from(input.trim())
+}
+
+pub fn welcome_message(user: &User, place: &Place){
+    let welcome = &place.settings().welcome_message;
+    if !welcome.is_empty() {
+        println!("Welcome to {}, {}!\n{}", place.name, user.name(), welcome);
     }
+}
+
+pub fn sent_message(user: &User, place: &Place, channel: u64, message: &str){
+    let channel = match place.find_channel_by_id(channel) {
+        Some(channel) => channel.name.clone(),
+        None => String::from("?"),
+    };
+    println!("User {} said in {} #{}: {}", user.name(), place.name, channel, message);
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
 
-pub fn sent_message(user: &User, place: &Place, message: &str){
-    println!("User {} said in {}: {}", user.name(), place.name, message);
-}
\ No newline at end of file
+    #[test]
+    fn check_registration_entries() {
+        let data = LoginData::from_registration(" test ", "secret pass".to_string(), "secret pass").unwrap();
+        assert_eq!("test", data.login());
+        assert_eq!("secret pass", data.password());
+        assert!(LoginData::from_registration("test", "secret pass".to_string(), "secret pas").is_err());
+        assert!(LoginData::from_registration("", "secret pass".to_string(), "secret pass").is_err());
+        assert!(LoginData::from_registration("test", String::new(), "").is_err());
+    }
+}
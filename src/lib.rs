@@ -1,22 +1,95 @@
 pub mod io;
 pub mod user;
 pub mod place;
+pub mod commands;
+pub mod auth;
+pub mod mail;
 
-use user::User;
-use place::{roles::{RolePerms}, Place};
-use std::{cell::RefCell, rc::{Rc, Weak}, time::SystemTime};
+use user::{FriendInfo, FriendRequest, JoinedPlace, Presence, ProfileField, Suspension, User};
+use place::{roles::{Permissions, RolePerms}, Channel, Invite, MessageKind, OverrideTarget, JoinRequest, Place, PlaceListing, PlaceSettings, PlaceSort, RequestStatus, Visibility};
+use mail::{Mail, MailTransport};
+use rand::{distributions::Alphanumeric, Rng};
+use std::{cell::RefCell, rc::{Rc, Weak}, time::{Duration, SystemTime}};
+
+// Returned by log_in, the reason can be read with login_suspension
+pub const ACCOUNT_SUSPENDED: &str = "This account is suspended.";
+// Seeds the password of the built-in Admin account on first start
+pub const ADMIN_PASSWORD_ENV: &str = "NAS_ADMIN_PASSWORD";
+pub const ADMIN_LOGIN: &str = "Admin";
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const LOGIN_FAILED: &str = "Logging failed. Wrong login or password.";
+pub const TOO_MANY_ATTEMPTS: &str = "Too many failed attempts. Try again later.";
+// Returned by privileged calls until a seeded password is changed
+pub const PASSWORD_CHANGE_REQUIRED: &str = "Change your password first";
+// Returned by log_in when the password was right, finish with complete_login
+pub const SECOND_FACTOR_REQUIRED: &str = "Second factor required. Enter the code from your authenticator app.";
+pub const INVALID_CODE: &str = "Invalid code.";
+pub const VERIFY_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+pub const RESET_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+// Source of logins typed into this process
+pub const LOCAL_CONNECTION: &str = "console";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword
+}
+
+// Token sent by mail, used once. Only its hash is kept.
+#[derive(Debug)]
+struct MailToken {
+    token_hash: String,
+    user_id: u64,
+    purpose: TokenPurpose,
+    expires: SystemTime
+}
+
+impl MailToken {
+    fn new(token: &str, user_id: u64, purpose: TokenPurpose) -> MailToken {
+        let lifetime = match purpose {
+            TokenPurpose::VerifyEmail => VERIFY_TOKEN_LIFETIME,
+            TokenPurpose::ResetPassword => RESET_TOKEN_LIFETIME,
+        };
+        MailToken {
+            token_hash: auth::hash_token(token),
+            user_id,
+            purpose,
+            expires: SystemTime::now() + lifetime
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Simulation{
     current_user: Weak<RefCell<User>>,
     members: Vec<Rc<RefCell<User>>>,
-    places: Vec<Rc<RefCell<Place>>>
+    places: Vec<Rc<RefCell<Place>>>,
+    next_place_id: u64,
+    next_user_id: u64,
+    friend_requests: Vec<FriendRequest>,
+    login_throttle: auth::LoginThrottle,
+    // Password checked, waiting for the second factor; with the connection
+    pending_login: Option<(Weak<RefCell<User>>, String)>,
+    // Suspension behind the last ACCOUNT_SUSPENDED, only after a right password
+    login_suspension: Option<Suspension>,
+    mail: Box<dyn MailTransport>,
+    mail_tokens: Vec<MailToken>,
+    sessions: Vec<auth::Session>,
+    next_session_id: u64,
+    current_session: Option<u64>
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Simulation{
     /*
     
-    Add checking for exising id numbers in methods 'get_user_next_id' and 'get_place_next_id'
+    Add checking for exising id numbers in method 'get_user_next_id'
     While creating user check if login/e-mail is unique
 
     */
@@ -28,18 +101,212 @@ impl Simulation{
             members: vec![],
             places: vec![],
             current_user: Weak::new(),
+            next_place_id: 0,
+            next_user_id: 0,
+            friend_requests: vec![],
+            login_throttle: auth::LoginThrottle::new(),
+            pending_login: None,
+            login_suspension: None,
+            mail: Box::new(mail::MemoryOutbox::new()),
+            mail_tokens: vec![],
+            sessions: vec![],
+            next_session_id: 0,
+            current_session: None,
         };
         // create default place
-        let place = Place::new("Default".to_string(), None, 0);
+        let place = Place::new("Default".to_string(), None, s.get_next_place_id());
         s.places.push(Rc::from(RefCell::new(place)));
 
-        s.create_user("Admin".to_string(), "***".to_string());
+        // Disabled until it gets a password, see set_admin_password
+        let admin = s.create_user(ADMIN_LOGIN.to_string(), String::new());
+        s.get_user_by_id(admin).unwrap().borrow_mut().set_operator(true);
+        s
+    }
+
+    // Same as new, with the Admin password taken from ADMIN_PASSWORD_ENV if it's set
+    pub fn from_env() -> Simulation {
+        let mut s = Simulation::new();
+        if let Ok(pass) = std::env::var(ADMIN_PASSWORD_ENV) {
+            if !pass.is_empty() {
+                s.set_admin_password(pass).expect("Fresh Admin has no password");
+            }
+        }
         s
     }
+
+    // Gives the disabled Admin account a first password, which has to be changed on login
+    pub fn set_admin_password(&mut self, pass: String) -> Result<(), &'static str> {
+        let admin = self.members.iter()
+            .find(|u| u.borrow().login() == ADMIN_LOGIN)
+            .ok_or("Cannot find the Admin account")?;
+        if admin.borrow().has_password() {return Err("Admin password is already set");}
+        if pass.is_empty() {return Err("Password cannot be empty");}
+        admin.borrow_mut().set_password(pass);
+        admin.borrow_mut().require_password_change();
+        Ok(())
+    }
+
+    pub fn is_admin_enabled(&self) -> bool {
+        self.members.iter().any(|u| u.borrow().login() == ADMIN_LOGIN && u.borrow().has_password())
+    }
+
+    pub fn password_change_required(&self) -> bool {
+        self.current_user.upgrade().is_some_and(|u| u.borrow().must_change_password())
+    }
+
+    // Current user, unless a forced password change is still pending
+    fn privileged_user(&self) -> Result<Rc<RefCell<User>>, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        if user.borrow().must_change_password() {return Err(PASSWORD_CHANGE_REQUIRED);}
+        Ok(user)
+    }
+
+    pub fn change_password(&mut self, old: &str, new: &str) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        if user.borrow().pass() != old {return Err("Wrong password");}
+        Self::check_new_password(new)?;
+        if new == old {return Err("New password has to be different");}
+        user.borrow_mut().set_password(new.to_string());
+        // Other devices have to log in with the new password
+        let id = user.borrow().id();
+        self.revoke_sessions_of(id);
+        Ok(())
+    }
+
+    fn check_new_password(pass: &str) -> Result<(), &'static str> {
+        if pass.chars().count() < MIN_PASSWORD_LENGTH {return Err("Password should have at least 8 characters");}
+        Ok(())
+    }
+
+    // E-mail
+    pub fn set_mail_transport(&mut self, transport: Box<dyn MailTransport>) {
+        self.mail = transport;
+    }
+
+    fn check_email(&self, email: &str, except: Option<u64>) -> Result<(), &'static str> {
+        let valid = match email.split_once('@') {
+            Some((name, domain)) => !name.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace),
+            None => false,
+        };
+        if !valid {return Err("This is not a valid e-mail address");}
+        let taken = self.members.iter().any(|u| {
+            let u = u.borrow();
+            Some(u.id()) != except && u.data.email.eq_ignore_ascii_case(email)
+        });
+        if taken {return Err("This e-mail address is already used");}
+        Ok(())
+    }
+
+    // Checked version of create_user, an e-mail address gets a verification mail
+    pub fn register_user(&mut self, data: &io::LoginData, email: Option<&str>) -> Result<u64, &'static str> {
+        if data.login().is_empty() {return Err("Login cannot be empty");}
+        // The login is the display name until one is set
+        if data.login().chars().all(|c| c.is_ascii_digit()) {return Err("Login cannot be only digits");}
+        if self.is_name_taken(&data.login(), None) {return Err("This login is already taken");}
+        Self::check_new_password(&data.password())?;
+        if let Some(email) = email {
+            self.check_email(email, None)?;
+            // Mailed before the account exists, so a failed send leaves nothing taken
+            let id = self.next_user_id;
+            if let Err(err) = self.mail_verification(id, email) {
+                self.mail_tokens.retain(|t| t.user_id != id);
+                return Err(err);
+            }
+        }
+        let id = self.create_user(data.login(), data.password());
+        if let Some(email) = email {
+            self.get_user_by_id(id)?.borrow_mut().data.email = email.to_string();
+        }
+        Ok(id)
+    }
+
+    // Changing the address needs a new verification
+    pub fn set_email(&mut self, email: &str) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        self.check_email(email, Some(id))?;
+        let user = self.get_user_by_id(id)?;
+        user.borrow_mut().data.email = email.to_string();
+        user.borrow_mut().data.email_verified = false;
+        self.send_verification(id)
+    }
+
+    fn new_mail_token(&mut self, user_id: u64, purpose: TokenPurpose) -> String {
+        let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        // One live token per purpose
+        self.mail_tokens.retain(|t| !(t.user_id == user_id && t.purpose == purpose));
+        self.mail_tokens.push(MailToken::new(&token, user_id, purpose));
+        token
+    }
+
+    fn send_verification(&mut self, user_id: u64) -> Result<(), &'static str> {
+        let email = self.get_user_by_id(user_id)?.borrow().data.email.clone();
+        self.mail_verification(user_id, &email)
+    }
+
+    fn mail_verification(&mut self, user_id: u64, email: &str) -> Result<(), &'static str> {
+        let token = self.new_mail_token(user_id, TokenPurpose::VerifyEmail);
+        let mail = Mail::new(email, "Verify your e-mail", format!("Use this token to verify your address: {token}"));
+        self.mail.send(&mail)
+    }
+
+    pub fn resend_verification(&mut self) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        let user = self.get_user_by_id(id)?;
+        if user.borrow().data.email.is_empty() {return Err("Set an e-mail address first");}
+        if user.borrow().data.email_verified {return Err("Your e-mail is already verified");}
+        self.send_verification(id)
+    }
+
+    fn take_mail_token(&mut self, token: &str, purpose: TokenPurpose) -> Result<u64, &'static str> {
+        let now = SystemTime::now();
+        self.mail_tokens.retain(|t| t.expires > now);
+        let hash = auth::hash_token(token.trim());
+        let index = self.mail_tokens.iter()
+            .position(|t| t.token_hash == hash && t.purpose == purpose)
+            .ok_or("Invalid or expired token")?;
+        Ok(self.mail_tokens.remove(index).user_id)
+    }
+
+    pub fn verify_email(&mut self, token: &str) -> Result<(), &'static str> {
+        let id = self.take_mail_token(token, TokenPurpose::VerifyEmail)?;
+        self.get_user_by_id(id)?.borrow_mut().data.email_verified = true;
+        Ok(())
+    }
+
+    // Always looks successful, so it can't be used to find addresses
+    pub fn request_password_reset(&mut self, email: &str) -> Result<(), &'static str> {
+        let user = self.members.iter()
+            .find(|u| u.borrow().data.email_verified && u.borrow().data.email.eq_ignore_ascii_case(email.trim()))
+            .map(Rc::clone);
+        if let Some(user) = user {
+            let (id, email) = (user.borrow().id(), user.borrow().data.email.clone());
+            let token = self.new_mail_token(id, TokenPurpose::ResetPassword);
+            let mail = Mail::new(&email, "Password reset", format!("Use this token to set a new password: {token}\nIgnore this mail if you didn't ask for it."));
+            // A send error would only show up for real accounts, so it's dropped
+            if self.mail.send(&mail).is_err() {
+                self.mail_tokens.retain(|t| !(t.user_id == id && t.purpose == TokenPurpose::ResetPassword));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reset_password(&mut self, token: &str, new: &str) -> Result<(), &'static str> {
+        Self::check_new_password(new)?;
+        let id = self.take_mail_token(token, TokenPurpose::ResetPassword)?;
+        let user = self.get_user_by_id(id)?;
+        user.borrow_mut().set_password(new.to_string());
+        // Owning the mailbox is proof enough to lift a lockout
+        let login = user.borrow().login();
+        self.login_throttle.unlock(&login);
+        self.revoke_sessions_of(id);
+        Ok(())
+    }
     
     // User methods
-    fn get_next_user_id(&self) -> u64 {
-        self.members.len() as u64
+    // Ids of deleted users are never reused
+    fn get_next_user_id(&mut self) -> u64 {
+        self.next_user_id += 1;
+        self.next_user_id - 1
     }
 
     pub fn create_user(&mut self, name: String, pass: String) -> u64 {
@@ -54,12 +321,225 @@ impl Simulation{
     pub fn get_user_by_id(&self, id: u64) -> Result<Rc<RefCell<User>>, &'static str> {
         for x in self.members.iter(){
             if x.borrow().id() == id{
-                return Ok(Rc::clone(&x))
+                return Ok(Rc::clone(x))
             }
         }
         Err("Cannot find the user. Make sure you have the correct id.")
     }
 
+    // Deletes the current user's account after checking the password.
+    // Owned places have to be transferred or deleted first.
+    pub fn delete_account(&mut self, password: &str) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        if user.borrow().pass() != password {return Err("Wrong password");}
+        let id = user.borrow().id();
+        if self.places.iter().any(|p| p.borrow().is_owner(id)) {
+            return Err("Transfer or delete your places first");
+        }
+        for place in self.places.iter() {
+            place.borrow_mut().erase_user(id);
+        }
+        for other in self.members.iter().filter(|u| !Rc::ptr_eq(u, &user)) {
+            other.borrow_mut().data.remove_friend(id);
+            other.borrow_mut().data.unblock(id);
+        }
+        self.friend_requests.retain(|r| r.from != id && r.to != id);
+        self.mail_tokens.retain(|t| t.user_id != id);
+        self.revoke_sessions_of(id);
+        // Dropping the last Rc releases the login, messages fall back to a placeholder
+        self.members.retain(|u| !Rc::ptr_eq(u, &user));
+        self.current_user = Weak::new();
+        Ok(())
+    }
+
+    // Operators
+    pub fn is_operator(&self, user_id: u64) -> bool {
+        self.get_user_by_id(user_id).is_ok_and(|u| u.borrow().is_operator())
+    }
+
+    fn current_operator(&self) -> Result<u64, &'static str> {
+        let id = self.privileged_user()?.borrow().id();
+        if !self.is_operator(id) {return Err("Only operators can do this");}
+        Ok(id)
+    }
+
+    pub fn set_operator(&mut self, user_id: u64, operator: bool) -> Result<(), &'static str> {
+        let id = self.current_operator()?;
+        if id == user_id && !operator {return Err("You cannot remove your own operator status");}
+        self.get_user_by_id(user_id)?.borrow_mut().set_operator(operator);
+        Ok(())
+    }
+
+    // Suspends an account in every place, None lasts until lifted
+    pub fn suspend_user(&mut self, user_id: u64, reason: String, duration: Option<Duration>) -> Result<(), &'static str> {
+        let id = self.current_operator()?;
+        let user = self.get_user_by_id(user_id)?;
+        if user.borrow().is_operator() {return Err("Operators cannot be suspended");}
+        user.borrow_mut().suspend(Suspension::new(reason, id, duration));
+        self.revoke_sessions_of(user_id);
+        Ok(())
+    }
+
+    pub fn unsuspend_user(&mut self, user_id: u64) -> Result<(), &'static str> {
+        self.current_operator()?;
+        let lifted = self.get_user_by_id(user_id)?.borrow_mut().lift_suspension();
+        if !lifted {return Err("This user is not suspended");}
+        Ok(())
+    }
+
+    // Id, login or display name
+    pub fn find_user(&self, query: &str) -> Result<Rc<RefCell<User>>, &'static str> {
+        if let Ok(id) = query.parse() {
+            return self.get_user_by_id(id);
+        }
+        self.members.iter()
+            .find(|u| u.borrow().login() == query || u.borrow().name().eq_ignore_ascii_case(query))
+            .map(Rc::clone)
+            .ok_or("Cannot find the user. Make sure you have the correct id.")
+    }
+
+    // Logins and display names can't repeat another user's name or login
+    fn is_name_taken(&self, name: &str, except: Option<u64>) -> bool {
+        self.members.iter().any(|u| {
+            let u = u.borrow();
+            Some(u.id()) != except && (u.name().eq_ignore_ascii_case(name) || u.login().eq_ignore_ascii_case(name))
+        })
+    }
+
+    pub fn edit_profile(&mut self, field: ProfileField, value: &str) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let id = user.borrow().id();
+        let x = match field {
+            ProfileField::DisplayName => {
+                if self.is_name_taken(value.trim(), Some(id)) {return Err("This name is already taken");}
+                user.borrow_mut().set_display_name(value)
+            }
+            ProfileField::Bio => user.borrow_mut().set_bio(value),
+            ProfileField::Pronouns => user.borrow_mut().set_pronouns(value),
+            ProfileField::Status => user.borrow_mut().set_status(value),
+        };
+        x
+    }
+
+    // Friends
+    fn current_user_id(&self) -> Result<u64, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let id = user.borrow().id();
+        Ok(id)
+    }
+
+    // Sending a request to someone who already asked us accepts theirs
+    pub fn send_friend_request(&mut self, to: u64) -> Result<&'static str, &'static str> {
+        let from = self.current_user_id()?;
+        if from == to {return Err("You cannot befriend yourself");}
+        let target = self.get_user_by_id(to)?;
+        if target.borrow().data.is_friend(from) {return Err("You are already friends");}
+        if target.borrow().data.has_blocked(from) {return Err("You cannot send a friend request to this user");}
+        if self.get_user_by_id(from)?.borrow().data.has_blocked(to) {return Err("Unblock this user first");}
+        if self.friend_requests.iter().any(|r| r.from == to && r.to == from) {
+            self.accept_friend_request(to)?;
+            return Ok("Friend request accepted");
+        }
+        if self.friend_requests.iter().any(|r| r.from == from && r.to == to) {
+            return Err("Friend request already sent");
+        }
+        self.friend_requests.push(FriendRequest::new(from, to));
+        Ok("Friend request sent")
+    }
+
+    fn take_friend_request(&mut self, from: u64, to: u64) -> Result<FriendRequest, &'static str> {
+        let index = self.friend_requests.iter()
+            .position(|r| r.from == from && r.to == to)
+            .ok_or("Cannot find the friend request")?;
+        Ok(self.friend_requests.remove(index))
+    }
+
+    pub fn accept_friend_request(&mut self, from: u64) -> Result<(), &'static str> {
+        let to = self.current_user_id()?;
+        let sender = self.get_user_by_id(from)?;
+        self.take_friend_request(from, to)?;
+        sender.borrow_mut().data.add_friend(to);
+        self.get_user_by_id(to)?.borrow_mut().data.add_friend(from);
+        Ok(())
+    }
+
+    pub fn decline_friend_request(&mut self, from: u64) -> Result<(), &'static str> {
+        let to = self.current_user_id()?;
+        self.take_friend_request(from, to)?;
+        Ok(())
+    }
+
+    pub fn cancel_friend_request(&mut self, to: u64) -> Result<(), &'static str> {
+        let from = self.current_user_id()?;
+        self.take_friend_request(from, to)?;
+        Ok(())
+    }
+
+    pub fn remove_friend(&mut self, friend: u64) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        let removed = self.get_user_by_id(id)?.borrow_mut().data.remove_friend(friend);
+        if !removed {return Err("This user is not your friend");}
+        if let Ok(other) = self.get_user_by_id(friend) {
+            other.borrow_mut().data.remove_friend(id);
+        }
+        Ok(())
+    }
+
+    // Blocking also ends the friendship and drops requests both ways
+    pub fn block_user(&mut self, target: u64) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        if id == target {return Err("You cannot block yourself");}
+        let other = self.get_user_by_id(target)?;
+        self.get_user_by_id(id)?.borrow_mut().data.block(target);
+        self.get_user_by_id(id)?.borrow_mut().data.remove_friend(target);
+        other.borrow_mut().data.remove_friend(id);
+        self.friend_requests.retain(|r| !((r.from == id && r.to == target) || (r.from == target && r.to == id)));
+        Ok(())
+    }
+
+    pub fn unblock_user(&mut self, target: u64) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        let removed = self.get_user_by_id(id)?.borrow_mut().data.unblock(target);
+        if !removed {return Err("This user is not blocked");}
+        Ok(())
+    }
+
+    // (incoming, outgoing) requests of the current user
+    pub fn return_friend_requests(&self) -> (Vec<FriendRequest>, Vec<FriendRequest>) {
+        let id = match self.current_user_id() {
+            Ok(id) => id,
+            Err(_) => {return (vec![], vec![]);}
+        };
+        let incoming = self.friend_requests.iter().filter(|r| r.to == id).cloned().collect();
+        let outgoing = self.friend_requests.iter().filter(|r| r.from == id).cloned().collect();
+        (incoming, outgoing)
+    }
+
+    // Places that aren't public only show up to their members
+    pub fn return_friends(&self) -> Vec<FriendInfo> {
+        let user = match self.current_user.upgrade() {
+            Some(user) => user,
+            None => {return vec![];}
+        };
+        let user_id = user.borrow().id();
+        let ids = user.borrow().data.friends().clone();
+        ids.into_iter()
+            .filter_map(|id| self.get_user_by_id(id).ok())
+            .map(|friend| {
+                let friend = friend.borrow();
+                let place = friend.place.borrow();
+                let visible = place.visibility() == Visibility::Public || place.find_user_by_id(user_id).is_some();
+                let place = if visible {Some(place.name.clone())} else {None};
+                FriendInfo {
+                    id: friend.id(),
+                    name: friend.name(),
+                    presence: friend.presence(),
+                    place
+                }
+            })
+            .collect()
+    }
+
     // Place methods
     fn get_default_place(&self) -> Rc<RefCell<Place>> {
         self.get_place_by_id(0).unwrap()
@@ -67,11 +547,25 @@ impl Simulation{
 
     fn add_to_default_place(&mut self, user: Rc<RefCell<User>>) {
         let place = self.get_place_by_id(0).unwrap();
-        place.borrow_mut().add_user(user);
+        Self::add_member(&user, &place);
     }
 
-    fn get_next_place_id(&self) -> u64 {
-        self.places.len() as u64
+    // Every join goes through here so the user's list and the history stay in sync
+    fn add_member(user: &Rc<RefCell<User>>, place: &Rc<RefCell<Place>>) {
+        place.borrow_mut().add_user(Rc::clone(user));
+        Self::record_join(user, place);
+    }
+
+    fn record_join(user: &Rc<RefCell<User>>, place: &Rc<RefCell<Place>>) {
+        let place_id = place.borrow().id();
+        user.borrow_mut().add_joined_place(place_id);
+        place.borrow_mut().add_event(user, MessageKind::Joined);
+    }
+
+    // Ids of deleted places are never given out again
+    fn get_next_place_id(&mut self) -> u64 {
+        self.next_place_id += 1;
+        self.next_place_id - 1
     }
 
     pub fn get_place_by_id(&self, id: u64) -> Result<Rc<RefCell<Place>>, &'static str> {
@@ -86,54 +580,394 @@ impl Simulation{
     pub fn return_current_place(&self) -> Rc<RefCell<Place>> {
         let user = match self.current_user.upgrade() {
             Some(user) => user,
-            None => {return Rc::clone(&self.get_default_place());}
+            None => {return self.get_default_place();}
         };
         let place = Rc::clone(&user.borrow().place);
         place
     }
 
     pub fn create_place(&mut self, name: String, admin_id: u64) -> u64 {
-        let admin = match self.get_user_by_id(admin_id) {
-            Ok(x) => Some(x),
-            Err(_) => None
-        };
+        let admin = self.get_user_by_id(admin_id).ok();
         let num = self.get_next_place_id();
-        let place = Place::new(name, admin, num);
-        self.places.push(Rc::from(RefCell::new(place)));
+        let place = Rc::from(RefCell::new(Place::new(name, admin.clone(), num)));
+        // Owner is added by the place itself
+        if let Some(user) = admin {
+            Self::record_join(&user, &place);
+        }
+        self.places.push(place);
         num
     }
 
+    // Places the current user can see, optionally filtered by name.
+    // Hidden places only show up for their members.
+    pub fn place_directory(&self, search: Option<&str>, sort: PlaceSort) -> Vec<PlaceListing> {
+        let user_id = self.current_user.upgrade().map(|u| u.borrow().id());
+        let search = search.map(|s| s.to_lowercase());
+        let mut vec: Vec<PlaceListing> = vec![];
+        for place in self.places.iter() {
+            let place = place.borrow();
+            if let Some(search) = &search {
+                if !place.name.to_lowercase().contains(search.as_str()) {continue;}
+            }
+            let listing = match user_id {
+                Some(id) => place.listing_for(id),
+                // Nobody logged in, treat as an outsider
+                None => place.listing_for(u64::MAX),
+            };
+            if !place.is_listed() && !listing.is_member {continue;}
+            vec.push(listing);
+        }
+        match sort {
+            PlaceSort::Id => vec.sort_by_key(|p| p.id),
+            PlaceSort::Name => vec.sort_by_key(|p| p.name.to_lowercase()),
+            PlaceSort::Members => vec.sort_by_key(|p| std::cmp::Reverse(p.member_count)),
+            PlaceSort::Activity => vec.sort_by_key(|p| std::cmp::Reverse(p.last_activity)),
+        }
+        vec
+    }
+
+    // Current user has to own the place
+    fn owned_place(&self, place_id: u64) -> Result<Rc<RefCell<Place>>, &'static str> {
+        let user = self.privileged_user()?;
+        let place = self.get_place_by_id(place_id)?;
+        if !place.borrow().is_owner(user.borrow().id()) {
+            return Err("Only the owner of the place can do that");
+        }
+        Ok(place)
+    }
+
+    pub fn transfer_ownership(&mut self, place_id: u64, new_owner: u64) -> Result<(), &'static str> {
+        let place = self.owned_place(place_id)?;
+        let x = place.borrow_mut().transfer_ownership(new_owner);
+        x
+    }
+
+    // Users looking at the place are moved back to the default one
+    pub fn delete_place(&mut self, place_id: u64) -> Result<(), &'static str> {
+        if place_id == 0 {return Err("The default place can't be deleted");}
+        let place = self.owned_place(place_id)?;
+        let default = self.get_default_place();
+        for user in self.members.iter() {
+            if Rc::ptr_eq(&user.borrow().place, &place) {
+                Self::move_user(user, &default);
+            }
+            user.borrow_mut().remove_joined_place(place_id);
+        }
+        self.places.retain(|p| !Rc::ptr_eq(p, &place));
+        Ok(())
+    }
+
+    // Perms in the current channel
     pub fn return_current_user_perms(&self) -> RolePerms {
         let place = self.return_current_place();
-        let x = place.borrow().return_perms(self.return_current_user().unwrap().borrow().id());
+        let x = place.borrow().return_perms(self.return_current_user().unwrap().borrow().id(), Some(self.return_current_channel()));
         x
     }
 
-    // Loggin system
-    pub fn logged(&self) -> bool {
+    pub fn explain_current_user_perms(&self) -> Vec<place::roles::PermSource> {
+        let place = self.return_current_place();
+        let x = place.borrow().explain_perms(self.return_current_user().unwrap().borrow().id(), Some(self.return_current_channel()));
+        x
+    }
+
+    // Channels
+    pub fn return_current_channel(&self) -> u64 {
         match self.current_user.upgrade() {
-            Some(_) => true,
-            None => false
+            Some(user) => user.borrow().channel,
+            None => place::GENERAL_CHANNEL_ID,
+        }
+    }
+
+    // Entering another place starts in its general channel.
+    // New members see the welcome message on their first visit, however they joined.
+    fn move_user(user: &Rc<RefCell<User>>, place: &Rc<RefCell<Place>>) {
+        if !Rc::ptr_eq(&user.borrow().place, place) {
+            user.borrow_mut().channel = place::GENERAL_CHANNEL_ID;
         }
+        user.borrow_mut().place = Rc::clone(place);
+        let place_id = place.borrow().id();
+        user.borrow_mut().mark_visited(place_id);
+        let first_visit = place.borrow_mut().take_welcome(user.borrow().id());
+        if first_visit {
+            io::welcome_message(&user.borrow(), &place.borrow());
+        }
+    }
+
+    pub fn switch_channel(&mut self, channel_id: u64) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let place = self.return_current_place();
+        if place.borrow().find_channel_by_id(channel_id).is_none() {return Err("Can't find the channel");}
+        if !place.borrow().has_channel_perm(user.borrow().id(), channel_id, Permissions::READ_HISTORY) {
+            return Err("You can't see this channel");
+        }
+        user.borrow_mut().channel = channel_id;
+        Ok(())
+    }
+
+    // Channels of the current place the current user can see
+    pub fn return_visible_channels(&self) -> Vec<String> {
+        let user_id = match self.current_user.upgrade() {
+            Some(user) => user.borrow().id(),
+            None => {return vec![];}
+        };
+        let place = self.return_current_place();
+        let place = place.borrow();
+        place.return_channels().iter()
+            .filter(|c| place.has_channel_perm(user_id, c.id(), Permissions::READ_HISTORY))
+            .map(|c| format!("{c}"))
+            .collect()
+    }
+
+    pub fn create_channel(&mut self, name: String) -> Result<u64, &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_PLACE)?;
+        let x = place.borrow_mut().add_channel(name);
+        x
     }
 
-    pub fn log_in(&mut self, login: String, password: String) -> Result<&'static str, &'static str>{
+    // Users looking at the channel go back to general
+    pub fn delete_channel(&mut self, channel_id: u64) -> Result<(), &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_PLACE)?;
+        place.borrow_mut().remove_channel(channel_id)?;
         for user in self.members.iter() {
-            if user.borrow().login() == login
-            {
-                if user.borrow().pass() == password {
-                    self.current_user = Rc::downgrade(user);
-                    return Ok("Logged in successfuly.")
-                }
-                else {
-                    return Err("Logging failed. Wrong login or password.")
-                }
+            let here = Rc::ptr_eq(&user.borrow().place, &place) && user.borrow().channel == channel_id;
+            if here {user.borrow_mut().channel = place::GENERAL_CHANNEL_ID;}
+        }
+        Ok(())
+    }
+
+    pub fn set_channel_topic(&mut self, channel_id: u64, topic: String) -> Result<(), &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_PLACE)?;
+        let mut place = place.borrow_mut();
+        let channel: &mut Channel = place.find_channel_by_id_mut(channel_id).ok_or("Can't find the channel")?;
+        channel.topic = topic;
+        Ok(())
+    }
+
+    pub fn set_channel_override(&mut self, channel_id: u64, target: OverrideTarget, allow: Permissions, deny: Permissions) -> Result<(), &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_ROLES)?;
+        let actor = self.current_user_id()?;
+        let mut place = place.borrow_mut();
+        place.check_override_rank(actor, target, allow)?;
+        place.set_channel_override(channel_id, target, allow, deny)
+    }
+
+    // Loggin system
+    pub fn logged(&self) -> bool {
+        self.current_user.upgrade().is_some()
+    }
+
+    // Returns a session token for resume_session
+    pub fn log_in(&mut self, login: String, password: String) -> Result<String, &'static str>{
+        self.log_in_from(login, password, LOCAL_CONNECTION)
+    }
+
+    // Source names the connection, for throttling attempts across logins.
+    // Failures give the same message whether the login exists or not.
+    pub fn log_in_from(&mut self, login: String, password: String, source: &str) -> Result<String, &'static str>{
+        let now = SystemTime::now();
+        self.login_suspension = None;
+        if !self.login_throttle.allows(&login, source, now) {
+            return Err(TOO_MANY_ATTEMPTS)
+        }
+        let user = self.members.iter()
+            .find(|u| u.borrow().login() == login && u.borrow().has_password() && u.borrow().pass() == password)
+            .map(Rc::clone);
+        let user = match user {
+            Some(user) => user,
+            None => {
+                self.login_throttle.record_failure(&login, source, now);
+                return Err(LOGIN_FAILED)
             }
+        };
+        if user.borrow().is_suspended() {
+            self.login_throttle.record_success(&login);
+            self.login_suspension = user.borrow().suspension().cloned();
+            return Err(ACCOUNT_SUSPENDED)
+        }
+        // Counter is reset only after the code, so guesses keep counting
+        if user.borrow().requires_second_factor() {
+            self.pending_login = Some((Rc::downgrade(&user), source.to_string()));
+            return Err(SECOND_FACTOR_REQUIRED)
+        }
+        self.login_throttle.record_success(&login);
+        Ok(self.finish_login(&user, source))
+    }
+
+    // Reason and end of the suspension that refused the last log_in
+    pub fn login_suspension(&self) -> Option<&Suspension> {
+        self.login_suspension.as_ref()
+    }
+
+    // Second step of log_in for accounts with two factor login.
+    // Takes a code from the authenticator app or a recovery code.
+    pub fn complete_login(&mut self, code: &str) -> Result<String, &'static str> {
+        let (user, source) = self.pending_login.clone().ok_or("There is no login waiting for a code")?;
+        let user = user.upgrade().ok_or("There is no login waiting for a code")?;
+        let login = user.borrow().login();
+        let now = SystemTime::now();
+        if !self.login_throttle.allows(&login, &source, now) {
+            return Err(TOO_MANY_ATTEMPTS)
+        }
+        let valid = match user.borrow_mut().two_factor_mut() {
+            Some(two_factor) => two_factor.verify_code(code, now) || two_factor.use_recovery_code(code),
+            None => false,
+        };
+        if !valid {
+            self.login_throttle.record_failure(&login, &source, now);
+            return Err(INVALID_CODE)
+        }
+        self.pending_login = None;
+        self.login_throttle.record_success(&login);
+        Ok(self.finish_login(&user, &source))
+    }
+
+    pub fn is_second_factor_pending(&self) -> bool {
+        self.pending_login.as_ref().is_some_and(|(user, _)| user.upgrade().is_some())
+    }
+
+    // Opens a new session and returns its token
+    fn finish_login(&mut self, user: &Rc<RefCell<User>>, source: &str) -> String {
+        let (session, token) = auth::Session::new(self.next_session_id, user.borrow().id(), source);
+        self.next_session_id += 1;
+        self.enter_session(user, session.id);
+        self.sessions.push(session);
+        token
+    }
+
+    fn enter_session(&mut self, user: &Rc<RefCell<User>>, session_id: u64) {
+        // Only one user is logged in at a time
+        if let Some(previous) = self.current_user.upgrade() {
+            previous.borrow_mut().set_presence(Presence::Offline);
+            previous.borrow_mut().touch();
+        }
+        user.borrow_mut().set_presence(Presence::Online);
+        user.borrow_mut().touch();
+        self.current_user = Rc::downgrade(user);
+        self.current_session = Some(session_id);
+    }
+
+    // Logs in again with a token from log_in, without the password
+    pub fn resume_session(&mut self, token: &str) -> Result<(), &'static str> {
+        let now = SystemTime::now();
+        self.sessions.retain(|s| !s.is_expired(now));
+        let session = self.sessions.iter_mut().find(|s| s.matches(token)).ok_or("Invalid or expired session")?;
+        session.last_used = now;
+        let (session_id, user_id) = (session.id, session.user_id);
+        let user = self.get_user_by_id(user_id)?;
+        if user.borrow().is_suspended() {return Err(ACCOUNT_SUSPENDED);}
+        self.enter_session(&user, session_id);
+        Ok(())
+    }
+
+    // Active sessions of the current user
+    pub fn return_sessions(&self) -> Vec<auth::Session> {
+        let id = match self.current_user_id() {
+            Ok(id) => id,
+            Err(_) => {return vec![];}
+        };
+        let now = SystemTime::now();
+        self.sessions.iter().filter(|s| s.user_id == id && !s.is_expired(now)).cloned().collect()
+    }
+
+    pub fn current_session_id(&self) -> Option<u64> {
+        self.current_session.filter(|_| self.logged())
+    }
+
+    pub fn revoke_session(&mut self, session_id: u64) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        let index = self.sessions.iter()
+            .position(|s| s.id == session_id && s.user_id == id)
+            .ok_or("Cannot find the session")?;
+        self.sessions.remove(index);
+        self.drop_revoked_current_session();
+        Ok(())
+    }
+
+    pub fn revoke_all_sessions(&mut self) -> Result<(), &'static str> {
+        let id = self.current_user_id()?;
+        self.revoke_sessions_of(id);
+        Ok(())
+    }
+
+    fn revoke_sessions_of(&mut self, user_id: u64) {
+        self.sessions.retain(|s| s.user_id != user_id);
+        self.drop_revoked_current_session();
+    }
+
+    // The login goes on, but without a session to resume or show
+    fn drop_revoked_current_session(&mut self) {
+        if self.current_session.is_some_and(|id| !self.sessions.iter().any(|s| s.id == id)) {
+            self.current_session = None;
+        }
+    }
+
+    // Two factor login is offered to operators and place admins
+    pub fn can_use_two_factor(&self, user_id: u64) -> bool {
+        self.is_operator(user_id) || self.places.iter().any(|p| p.borrow().is_admin(user_id))
+    }
+
+    // Starts enrollment and returns the otpauth URI for the authenticator app
+    pub fn begin_totp_enrollment(&mut self) -> Result<String, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        if !self.can_use_two_factor(user.borrow().id()) {return Err("Two factor login is for admin accounts");}
+        if user.borrow().requires_second_factor() {return Err("Two factor login is already on");}
+        let totp = auth::Totp::generate();
+        let uri = totp.uri("nas", &user.borrow().login());
+        user.borrow_mut().set_two_factor(Some(auth::TwoFactor::new(totp)));
+        Ok(uri)
+    }
+
+    // First valid code turns two factor login on and gives the recovery codes
+    pub fn confirm_totp_enrollment(&mut self, code: &str) -> Result<Vec<String>, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let mut user = user.borrow_mut();
+        let two_factor = user.two_factor_mut().ok_or("Start the enrollment first")?;
+        if two_factor.confirmed {return Err("Two factor login is already on");}
+        if !two_factor.verify_code(code, SystemTime::now()) {return Err(INVALID_CODE);}
+        two_factor.confirmed = true;
+        Ok(two_factor.new_recovery_codes())
+    }
+
+    pub fn disable_two_factor(&mut self, code: &str) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let mut user = user.borrow_mut();
+        let two_factor = user.two_factor_mut().ok_or("Two factor login is off")?;
+        if !two_factor.verify_code(code, SystemTime::now()) && !two_factor.use_recovery_code(code) {
+            return Err(INVALID_CODE);
         }
-        Err("Logging failed. Can't find the user")
+        user.set_two_factor(None);
+        Ok(())
+    }
+
+    pub fn regenerate_recovery_codes(&mut self, code: &str) -> Result<Vec<String>, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let mut user = user.borrow_mut();
+        let two_factor = user.two_factor_mut().filter(|t| t.confirmed).ok_or("Two factor login is off")?;
+        if !two_factor.verify_code(code, SystemTime::now()) {return Err(INVALID_CODE);}
+        Ok(two_factor.new_recovery_codes())
+    }
+
+    // Lockouts, for operators
+    pub fn return_lockout_events(&self) -> Result<Vec<auth::LockoutEvent>, &'static str> {
+        self.current_operator()?;
+        Ok(self.login_throttle.events().clone())
+    }
+
+    pub fn unlock_login(&mut self, login: &str) -> Result<(), &'static str> {
+        self.current_operator()?;
+        if !self.login_throttle.unlock(login) {return Err("This login has no failed attempts");}
+        Ok(())
     }
 
+    // Ends the current session, its token can't be resumed afterwards
     pub fn log_off(&mut self){
+        if let Some(user) = self.current_user.upgrade() {
+            user.borrow_mut().set_presence(Presence::Offline);
+            user.borrow_mut().touch();
+        }
+        if let Some(session_id) = self.current_session.take() {
+            self.sessions.retain(|s| s.id != session_id);
+        }
         self.current_user = Weak::new();
         println!("Successfuly log off!");
     }
@@ -142,6 +976,17 @@ impl Simulation{
         self.current_user.upgrade()
     }
 
+    // Online or do not disturb, offline is set by log_off
+    pub fn set_presence(&mut self, presence: Presence) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        if matches!(presence, Presence::Idle | Presence::Offline) {
+            return Err("You can only pick online or do not disturb");
+        }
+        user.borrow_mut().set_presence(presence);
+        user.borrow_mut().touch();
+        Ok(())
+    }
+
     // Functions
     pub fn change_place(&mut self, place_id: u64) -> Option<&'static str>{
         let user =  self.current_user.upgrade().unwrap();
@@ -149,68 +994,387 @@ impl Simulation{
             Ok(x) => x,
             Err(err) => {return Some(err);}
         };
+        if place.borrow().is_banned(user.borrow().id()) {return Some("You are banned from this place");}
         // Add user if it's first time
-        user.borrow_mut().place = Rc::clone(&place);
         if place.borrow().find_user_by_id(user.borrow().id()).is_none() {
-            place.borrow_mut().add_user(Rc::clone(&user));
+            let visibility = place.borrow().visibility();
+            match visibility {
+                Visibility::Public => {},
+                Visibility::InviteOnly => {return Some("This place is invite only. You need an invite code.");}
+                Visibility::RequestToJoin => {
+                    let x = place.borrow_mut().add_join_request(user.borrow().id());
+                    return match x {
+                        Ok(_) => Some("Join request sent. Wait for an admin to approve it."),
+                        Err(err) => Some(err),
+                    };
+                }
+                // Don't tell outsiders it exists
+                Visibility::Hidden => {return Some("Cannot find the place. Make sure you have the correct id.");}
+            }
+            self.join_place(&user, &place);
         }
+        Self::move_user(&user, &place);
         None
         
     }
 
-    pub fn reset_place(&mut self){
-        self.change_place(0);
+    // First time in the place, the welcome comes with the move
+    fn join_place(&self, user: &Rc<RefCell<User>>, place: &Rc<RefCell<Place>>) {
+        Self::add_member(user, place);
+    }
+
+    // Gives up membership, the user goes back to the default place if they were looking at it
+    pub fn leave_place(&mut self, place_id: u64) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let user_id = user.borrow().id();
+        if place_id == 0 {return Err("You can't leave the default place");}
+        let place = self.get_place_by_id(place_id)?;
+        if place.borrow().find_user_by_id(user_id).is_none() {return Err("You are not a member of this place");}
+        if place.borrow().is_owner(user_id) {return Err("Transfer the ownership before leaving");}
+        place.borrow_mut().add_event(&user, MessageKind::Left);
+        place.borrow_mut().remove_user(user_id)?;
+        user.borrow_mut().remove_joined_place(place_id);
+        if Rc::ptr_eq(&user.borrow().place, &place) {
+            Self::move_user(&user, &self.get_default_place());
+        }
+        Ok(())
     }
 
-    pub fn send_message(&mut self, message: &str){
+    // Places the current user is a member of, in their order, with place names
+    pub fn return_joined_places(&self) -> Vec<(JoinedPlace, String)> {
         let user = match self.current_user.upgrade() {
-            Some(x) => x,
-            None => {return;}
+            Some(user) => user,
+            None => {return vec![];}
         };
-        let place = Rc::clone(&self.current_user.upgrade().unwrap().borrow().place);
-        io::sent_message(&user.borrow(), &place.borrow(), message);
-        let mess = place::PlaceMessage::new(&user, String::from(message), SystemTime::now());
-        place.borrow_mut().add_message(mess);
+        let joined = user.borrow().joined_places().clone();
+        joined.into_iter()
+            .filter_map(|j| {
+                let place = self.get_place_by_id(j.place_id).ok()?;
+                let name = place.borrow().name.clone();
+                Some((j, name))
+            })
+            .collect()
     }
 
-    pub fn return_current_place_messages(&self) -> Vec<String> {
-        let place = self.return_current_place();
-        let mut vec: Vec<String> = vec![];
-        for mesg in place.borrow().messages.iter() {
-            vec.push(format!("{mesg}"));
-        }
-        vec
+    // Switch to the nth place of the user's list, counted from 1
+    pub fn switch_to_joined_place(&mut self, position: usize) -> Result<u64, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let place_id = user.borrow().joined_place_at(position).ok_or("There is no place at this position")?;
+        if let Some(err) = self.change_place(place_id) {return Err(err);}
+        Ok(place_id)
     }
 
-    // Admin functions
-    pub fn is_admin(&self, user_id: u64) -> bool {
-        match self.return_current_place().borrow().find_admin(user_id) {
-            Some(_) => true,
-            None => false,
-        }
-        
+    pub fn set_favourite_place(&mut self, position: usize, favourite: bool) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let x = user.borrow_mut().set_favourite(position, favourite);
+        x
     }
 
-    pub fn is_admin_in_server(&self, user_id: u64, place_id: u64) -> bool {
-        let place = match self.get_place_by_id(place_id) {
-            Ok(place) => {
-                match place.borrow().find_admin(user_id) {
-                    Some(_) => true,
-                    None => false,
+    pub fn move_joined_place(&mut self, from: usize, to: usize) -> Result<(), &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let x = user.borrow_mut().move_joined_place(from, to);
+        x
+    }
+
+    // Joins whichever place the code belongs to and moves there
+    pub fn join_with_invite(&mut self, code: &str) -> Result<u64, &'static str> {
+        let user = self.current_user.upgrade().ok_or("You need to log in first")?;
+        let place = self.places.iter()
+            .find(|p| p.borrow().find_invite(code).is_some())
+            .map(Rc::clone)
+            .ok_or("Invalid invite code")?;
+        let user_id = user.borrow().id();
+        if place.borrow().is_banned(user_id) {return Err("You are banned from this place");}
+        if place.borrow().find_user_by_id(user_id).is_none() {
+            place.borrow_mut().use_invite(code)?;
+            self.join_place(&user, &place);
+        }
+        Self::move_user(&user, &place);
+        let id = place.borrow().id();
+        Ok(id)
+    }
+
+    // Invites
+    fn new_invite_code(&self) -> String {
+        loop {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect();
+            if !self.places.iter().any(|p| p.borrow().find_invite(&code).is_some()) {
+                return code;
+            }
+        }
+    }
+
+    // Invite to the current place, None means no limit
+    pub fn create_invite(&mut self, max_uses: Option<u32>, valid_for: Option<Duration>) -> Result<String, &'static str> {
+        let place = self.place_with_perm(Permissions::CREATE_INVITES)?;
+        if max_uses == Some(0) {return Err("Invite needs at least one use");}
+        let code = self.new_invite_code();
+        let user_id = self.current_user.upgrade().unwrap().borrow().id();
+        place.borrow_mut().add_invite(Invite::new(code.clone(), user_id, max_uses, valid_for));
+        Ok(code)
+    }
+
+    // Creator of the invite or place admins can revoke it
+    pub fn revoke_invite(&mut self, code: &str) -> Result<(), &'static str> {
+        let user = self.privileged_user()?;
+        let user_id = user.borrow().id();
+        let place = self.return_current_place();
+        let creator = place.borrow().find_invite(code).ok_or("Can't find the invite")?.created_by;
+        if creator != user_id && !place.borrow().is_admin(user_id) {
+            return Err("You don't have permission to do that");
+        }
+        let x = place.borrow_mut().revoke_invite(code);
+        x
+    }
+
+    // Valid invites of the current place. Admins see all of them, others only their own.
+    pub fn return_invites(&self) -> Result<Vec<Invite>, &'static str> {
+        let user_id = self.privileged_user()?.borrow().id();
+        let place = self.return_current_place();
+        let place = place.borrow();
+        let admin = place.is_admin(user_id);
+        Ok(place.return_invites().iter()
+            .filter(|i| i.is_valid() && (admin || i.created_by == user_id))
+            .cloned()
+            .collect())
+    }
+
+    // Join requests
+    pub fn return_pending_join_requests(&self) -> Result<Vec<JoinRequest>, &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_PLACE)?;
+        let x = place.borrow().return_join_requests().iter()
+            .filter(|r| r.status == RequestStatus::Pending)
+            .cloned()
+            .collect();
+        Ok(x)
+    }
+
+    pub fn approve_join_request(&mut self, request_id: u64, response: Option<String>) -> Result<(), &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_PLACE)?;
+        let user_id = place.borrow_mut().resolve_join_request(request_id, RequestStatus::Approved, response)?;
+        // Account could be gone by now
+        if let Ok(user) = self.get_user_by_id(user_id) {
+            if place.borrow().find_user_by_id(user_id).is_none() {
+                Self::add_member(&user, &place);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reject_join_request(&mut self, request_id: u64, response: Option<String>) -> Result<(), &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_PLACE)?;
+        let x = place.borrow_mut().resolve_join_request(request_id, RequestStatus::Rejected, response).map(|_| ());
+        x
+    }
+
+    // Join requests of the current user in every place, with place id and name
+    pub fn return_my_join_requests(&self) -> Vec<(u64, String, JoinRequest)> {
+        let user_id = match self.current_user.upgrade() {
+            Some(user) => user.borrow().id(),
+            None => {return vec![];}
+        };
+        let mut vec = vec![];
+        for place in self.places.iter() {
+            let place = place.borrow();
+            for r in place.return_join_requests().iter().filter(|r| r.user_id == user_id) {
+                vec.push((place.id(), place.name.clone(), r.clone()));
+            }
+        }
+        vec
+    }
+
+    pub fn update_place_settings(&mut self, place_id: u64, settings: PlaceSettings) -> Result<(), &'static str> {
+        let user = self.privileged_user()?;
+        let place = self.get_place_by_id(place_id)?;
+        let user_id = user.borrow().id();
+        if !place.borrow().has_perm(user_id, Permissions::MANAGE_PLACE) {
+            return Err("You don't have permission to do that");
+        }
+        // New members get the default role, so it's handed out like any other
+        if settings.default_role != place.borrow().settings().default_role {
+            if settings.default_role == place::roles::ADMIN_ROLE_ID {return Err("Admin can't be the default role");}
+            place.borrow().check_role_id_rank(user_id, settings.default_role, true)?;
+        }
+        let x = place.borrow_mut().update_settings(settings);
+        x
+    }
+
+    pub fn set_place_visibility(&mut self, place_id: u64, visibility: Visibility) -> Result<(), &'static str> {
+        let user = self.privileged_user()?;
+        let place = self.get_place_by_id(place_id)?;
+        if !place.borrow().has_perm(user.borrow().id(), Permissions::MANAGE_PLACE) {
+            return Err("You don't have permission to do that");
+        }
+        if place_id == 0 && visibility != Visibility::Public {
+            return Err("The default place has to stay public");
+        }
+        place.borrow_mut().set_visibility(visibility);
+        Ok(())
+    }
+
+    pub fn reset_place(&mut self){
+        self.change_place(0);
+    }
+
+    pub fn send_message(&mut self, message: &str) -> Result<(), &'static str> {
+        let user = match self.current_user.upgrade() {
+            Some(x) => x,
+            None => {return Err("You need to log in first");}
+        };
+        let place = Rc::clone(&self.current_user.upgrade().unwrap().borrow().place);
+        let channel = self.return_current_channel();
+        if !place.borrow().has_channel_perm(user.borrow().id(), channel, Permissions::SEND_MESSAGES) {
+            return Err("You can't send messages here");
+        }
+        if message.chars().count() > place.borrow().settings().max_message_length {
+            return Err("Message is too long for this place");
+        }
+        io::sent_message(&user.borrow(), &place.borrow(), channel, message);
+        let mess = place::PlaceMessage::new(&user, String::from(message), SystemTime::now());
+        place.borrow_mut().add_message(channel, mess)?;
+        user.borrow_mut().touch();
+        Ok(())
+    }
+
+    // Messages of the current channel
+    pub fn return_current_place_messages(&self) -> Vec<String> {
+        let place = self.return_current_place();
+        let mut vec: Vec<String> = vec![];
+        let channel_id = self.return_current_channel();
+        let mut blocked = vec![];
+        if let Some(user) = self.current_user.upgrade() {
+            if !place.borrow().has_channel_perm(user.borrow().id(), channel_id, Permissions::READ_HISTORY) {
+                return vec;
+            }
+            blocked = user.borrow().data.blocked().clone();
+        }
+        if let Some(channel) = place.borrow().find_channel_by_id(channel_id) {
+            // Runs of messages from blocked users are collapsed into one line
+            let mut hidden = 0;
+            for mesg in channel.messages.iter() {
+                if mesg.user_id().is_some_and(|id| blocked.contains(&id)) {
+                    hidden += 1;
+                    continue;
+                }
+                if hidden > 0 {
+                    vec.push(format!("[{hidden} message(s) from blocked users]"));
+                    hidden = 0;
                 }
-            },
+                vec.push(format!("{mesg}"));
+            }
+            if hidden > 0 {
+                vec.push(format!("[{hidden} message(s) from blocked users]"));
+            }
+        }
+        vec
+    }
+
+    // Permission checks
+    pub fn has_perm(&self, user_id: u64, perm: Permissions) -> bool {
+        self.return_current_place().borrow().has_perm(user_id, perm)
+    }
+
+    pub fn has_perm_in_place(&self, user_id: u64, place_id: u64, perm: Permissions) -> bool {
+        match self.get_place_by_id(place_id) {
+            Ok(place) => place.borrow().has_perm(user_id, perm),
             Err(_) => false,
-        };
-        place
+        }
+    }
+
+    // Admin status comes only from roles
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        self.return_current_place().borrow().is_admin(user_id)
+    }
+
+    pub fn is_admin_in_place(&self, user_id: u64, place_id: u64) -> bool {
+        match self.get_place_by_id(place_id) {
+            Ok(place) => place.borrow().is_admin(user_id),
+            Err(_) => false,
+        }
+    }
+
+    // Same as is_admin_in_place, kept under its old name
+    pub fn is_admin_in_server(&self, user_id: u64, place_id: u64) -> bool {
+        self.is_admin_in_place(user_id, place_id)
+    }
+
+    // Current place, if the current user holds the permission there
+    fn place_with_perm(&self, perm: Permissions) -> Result<Rc<RefCell<Place>>, &'static str> {
+        let user = self.privileged_user()?;
+        let place = self.return_current_place();
+        if !place.borrow().has_perm(user.borrow().id(), perm) {
+            return Err("You don't have permission to do that");
+        }
+        Ok(place)
+    }
+
+    // Role management
+    // Roles are managed top down, see Place::check_role_rank
+    pub fn create_role(&mut self, name: String, perms: RolePerms) -> Result<u64, &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_ROLES)?;
+        let actor = self.current_user_id()?;
+        let mut place = place.borrow_mut();
+        place.check_role_rank(actor, &perms)?;
+        place.create_role(name, perms)
+    }
+
+    pub fn rename_role(&mut self, role_id: u64, name: String) -> Result<(), &'static str> {
+        let place = self.place_with_role_rank(role_id, false)?;
+        let x = place.borrow_mut().rename_role(role_id, name);
+        x
+    }
+
+    // Both the role as it is and as it will be have to sit below the user
+    pub fn edit_role_perms(&mut self, role_id: u64, perms: RolePerms) -> Result<(), &'static str> {
+        let place = self.place_with_role_rank(role_id, false)?;
+        let actor = self.current_user_id()?;
+        let mut place = place.borrow_mut();
+        place.check_role_rank(actor, &perms)?;
+        place.edit_role_perms(role_id, perms)
+    }
+
+    pub fn delete_role(&mut self, role_id: u64) -> Result<(), &'static str> {
+        let place = self.place_with_role_rank(role_id, false)?;
+        let x = place.borrow_mut().delete_role(role_id);
+        x
     }
 
+    pub fn grant_role(&mut self, user_id: u64, role_id: u64) -> Result<(), &'static str> {
+        let place = self.place_with_role_rank(role_id, true)?;
+        let x = place.borrow_mut().grant_role(user_id, role_id);
+        x
+    }
+
+    pub fn revoke_role(&mut self, user_id: u64, role_id: u64) -> Result<(), &'static str> {
+        let place = self.place_with_role_rank(role_id, false)?;
+        let x = place.borrow_mut().revoke_role(user_id, role_id);
+        x
+    }
+
+    // Current place, if the current user can manage roles and sits above this one
+    fn place_with_role_rank(&self, role_id: u64, handing_out: bool) -> Result<Rc<RefCell<Place>>, &'static str> {
+        let place = self.place_with_perm(Permissions::MANAGE_ROLES)?;
+        let actor = self.current_user_id()?;
+        place.borrow().check_role_id_rank(actor, role_id, handing_out)?;
+        Ok(place)
+    }
+
+    // Admin functions
     pub fn ban_user(&mut self, user_id: u64) -> Result<bool, &'static str> {
-        let curr_user = Rc::clone(&self.current_user.upgrade().unwrap());
+        let curr_user = self.privileged_user()?;
         let curr_place = Rc::clone(&curr_user.borrow().place);
-        if self.is_admin(curr_user.borrow().id()) {
+        let curr_id = curr_user.borrow().id();
+        if self.has_perm(curr_id, Permissions::BAN) {
+            if curr_place.borrow().find_user_by_id(user_id).is_some() {
+                curr_place.borrow().check_member_rank(curr_id, user_id)?;
+            }
             return Ok(curr_place.borrow_mut().ban_user(user_id));
         }
-        Err("User not an admin")
+        Err("You don't have permission to ban users")
     } 
 
 }
@@ -223,7 +1387,7 @@ mod testing{
     fn check_logged_start(){
         let sim = Simulation::new();
         //println!("{:#?}", sim);
-        assert_eq!(false, sim.logged());
+        assert!(!sim.logged());
     }
 
     #[test]
@@ -251,5 +1415,951 @@ mod testing{
         let id = sim.create_user("test".to_string(), "1234".to_string());
         let place_id = sim.create_place("Debug".to_string(), id);
         assert!(sim.is_admin_in_server(id, place_id));
+        assert!(sim.has_perm_in_place(id, place_id, Permissions::BAN));
+        assert!(sim.has_perm_in_place(id, place_id, Permissions::MANAGE_ROLES));
+        assert!(sim.is_admin_in_place(id, place_id));
+        assert_eq!(Some(id), sim.get_place_by_id(place_id).unwrap().borrow().owner());
+    }
+
+    #[test]
+    fn check_admin_from_role() {
+        let mut sim = Simulation::new();
+        let user = sim.create_user("test".to_string(), "1234".to_string());
+        assert!(!sim.is_admin_in_place(user, 0));
+        sim.get_default_place().borrow_mut().grant_role(user, place::roles::ADMIN_ROLE_ID).unwrap();
+        assert!(sim.is_admin_in_place(user, 0));
+        sim.get_default_place().borrow_mut().revoke_role(user, place::roles::ADMIN_ROLE_ID).unwrap();
+        assert!(!sim.is_admin_in_place(user, 0));
+    }
+
+    #[test]
+    fn check_owner_transfer() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Debug".to_string(), owner);
+        let place = sim.get_place_by_id(place_id).unwrap();
+        // Not a member yet
+        assert!(place.borrow_mut().transfer_ownership(other).is_err());
+        place.borrow_mut().add_user(sim.get_user_by_id(other).unwrap());
+        place.borrow_mut().transfer_ownership(other).unwrap();
+        assert!(place.borrow().is_owner(other));
+        assert!(sim.is_admin_in_place(other, place_id));
+
+        // The owner can't lose the Admin role, the old one can
+        assert!(place.borrow_mut().revoke_role(other, place::roles::ADMIN_ROLE_ID).is_err());
+        assert!(sim.is_admin_in_place(other, place_id));
+        place.borrow_mut().revoke_role(owner, place::roles::ADMIN_ROLE_ID).unwrap();
+        assert!(!sim.is_admin_in_place(owner, place_id));
+        // One entry per member, the owner wasn't added twice
+        assert_eq!(2, place.borrow().members.len());
+    }
+
+    #[test]
+    fn check_banned_member() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+        sim.get_place_by_id(place_id).unwrap().borrow_mut().add_user(sim.get_user_by_id(other).unwrap());
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        sim.grant_role(other, place::roles::ADMIN_ROLE_ID).unwrap();
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        sim.change_place(place_id);
+        // Admins can't ban the owner
+        assert!(sim.ban_user(owner).is_err());
+        assert!(!sim.get_place_by_id(place_id).unwrap().borrow().is_banned(owner));
+        sim.log_off();
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert_eq!(Ok(true), sim.ban_user(other));
+        sim.log_off();
+
+        // Roles are kept on record, but do nothing while banned
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        assert!(!sim.is_admin_in_place(other, place_id));
+        assert!(sim.set_place_visibility(place_id, Visibility::Hidden).is_err());
+        assert!(sim.send_message("still here").is_err());
+        assert!(sim.change_place(0).is_none());
+        assert!(sim.change_place(place_id).is_some());
+        assert_eq!(0, sim.return_current_place().borrow().id());
+    }
+
+    #[test]
+    fn check_delete_place() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Debug".to_string(), owner);
+
+        // Someone else is looking at the place
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        sim.change_place(place_id);
+        assert!(sim.delete_place(place_id).is_err());
+        assert!(sim.transfer_ownership(place_id, other).is_err());
+        sim.log_off();
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.delete_place(0).is_err());
+        sim.delete_place(place_id).unwrap();
+        assert!(sim.get_place_by_id(place_id).is_err());
+        assert_eq!(0, sim.get_user_by_id(other).unwrap().borrow().place.borrow().id());
+        // Deleted ids aren't reused
+        assert_ne!(place_id, sim.create_place("Debug 2".to_string(), owner));
+    }
+
+    #[test]
+    fn check_invite_only() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Secret".to_string(), owner);
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.set_place_visibility(place_id, Visibility::InviteOnly).unwrap();
+        sim.change_place(place_id);
+        let code = sim.create_invite(Some(1), Some(Duration::from_secs(60))).unwrap();
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        assert!(sim.change_place(place_id).is_some());
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().find_user_by_id(other).is_none());
+        assert!(sim.join_with_invite("nope").is_err());
+        assert_eq!(Ok(place_id), sim.join_with_invite(&code));
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().find_user_by_id(other).is_some());
+        // Members come and go freely, the invite is used up
+        assert!(sim.change_place(0).is_none());
+        assert!(sim.change_place(place_id).is_none());
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().find_invite(&code).is_none());
+        // Basic members can't make invites, or see the ones of others
+        assert!(sim.create_invite(None, None).is_err());
+        sim.log_off();
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        let owner_code = sim.create_invite(None, None).unwrap();
+        assert_eq!(owner_code, sim.return_invites().unwrap()[0].code);
+        sim.log_off();
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        sim.change_place(place_id);
+        assert!(sim.return_invites().unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_expired_invite() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Hidden".to_string(), owner);
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.set_place_visibility(place_id, Visibility::Hidden).unwrap();
+        sim.change_place(place_id);
+        let expired = sim.create_invite(None, Some(Duration::ZERO)).unwrap();
+        let revoked = sim.create_invite(None, None).unwrap();
+        sim.revoke_invite(&revoked).unwrap();
+        assert!(!sim.get_place_by_id(place_id).unwrap().borrow().is_listed());
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        assert_eq!(Some("Cannot find the place. Make sure you have the correct id."), sim.change_place(place_id));
+        assert!(sim.join_with_invite(&expired).is_err());
+        assert!(sim.join_with_invite(&revoked).is_err());
+    }
+
+    #[test]
+    fn check_place_directory() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        sim.create_user("other".to_string(), "4321".to_string());
+        let rust = sim.create_place("Rust fans".to_string(), owner);
+        let hidden = sim.create_place("Rusty secrets".to_string(), owner);
+        let private = sim.create_place("Private".to_string(), owner);
+        let mut settings = PlaceSettings::new();
+        settings.description = "Only for us".to_string();
+        sim.get_place_by_id(private).unwrap().borrow_mut().update_settings(settings).unwrap();
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.set_place_visibility(hidden, Visibility::Hidden).unwrap();
+        sim.set_place_visibility(private, Visibility::InviteOnly).unwrap();
+        sim.change_place(rust);
+        sim.send_message("hello").unwrap();
+        // Members see hidden places they belong to
+        assert_eq!(2, sim.place_directory(Some("rust"), PlaceSort::Id).len());
+        let first = &sim.place_directory(None, PlaceSort::Activity)[0];
+        assert_eq!(rust, first.id);
+        assert!(first.is_member);
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        let list = sim.place_directory(Some("RUST"), PlaceSort::Name);
+        assert_eq!(1, list.len());
+        assert!(!list[0].is_member);
+        let list = sim.place_directory(None, PlaceSort::Members);
+        // Default place has everyone
+        assert_eq!(0, list[0].id);
+        let private = list.iter().find(|p| p.id == private).unwrap();
+        assert!(private.description.is_empty());
+    }
+
+    #[test]
+    fn check_place_settings() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        let role = sim.create_role("Newcomer".to_string(), RolePerms::new(Permissions::PIN, Permissions::empty(), 1)).unwrap();
+        let mut settings = sim.return_current_place().borrow().settings().clone();
+        settings.welcome_message = "Read the rules".to_string();
+        settings.max_message_length = 5;
+        settings.default_role = role;
+        sim.update_place_settings(place_id, settings.clone()).unwrap();
+        assert!(sim.send_message("too long").is_err());
+        assert!(sim.send_message("short").is_ok());
+        settings.default_role = 99;
+        assert!(sim.update_place_settings(place_id, settings).is_err());
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        assert!(sim.update_place_settings(place_id, PlaceSettings::new()).is_err());
+        sim.change_place(place_id);
+        assert!(sim.has_perm_in_place(other, place_id, Permissions::PIN));
+    }
+
+    #[test]
+    fn check_default_role_rank() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let manager = sim.create_user("other".to_string(), "4321".to_string());
+        let newcomer = sim.create_user("third".to_string(), "0000".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+        sim.get_place_by_id(place_id).unwrap().borrow_mut().add_user(sim.get_user_by_id(manager).unwrap());
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        let managers = sim.create_role("Manager".to_string(), RolePerms::new(Permissions::basic() | Permissions::MANAGE_PLACE, Permissions::empty(), 5)).unwrap();
+        let banner = sim.create_role("Banner".to_string(), RolePerms::new(Permissions::BAN, Permissions::empty(), 1)).unwrap();
+        sim.grant_role(manager, managers).unwrap();
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        let mut settings = sim.get_place_by_id(place_id).unwrap().borrow().settings().clone();
+        for role in [place::roles::ADMIN_ROLE_ID, managers, banner] {
+            settings.default_role = role;
+            assert!(sim.update_place_settings(place_id, settings.clone()).is_err());
+        }
+        // Other settings still work
+        settings.default_role = place::roles::BASIC_ROLE_ID;
+        settings.topic = "Hi".to_string();
+        sim.update_place_settings(place_id, settings).unwrap();
+        sim.log_off();
+
+        sim.log_in("third".to_string(), "0000".to_string()).unwrap();
+        sim.change_place(place_id);
+        assert!(!sim.is_admin_in_place(newcomer, place_id));
+        assert!(!sim.has_perm_in_place(newcomer, place_id, Permissions::BAN));
+    }
+
+    #[test]
+    fn check_channels() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        sim.send_message("in general").unwrap();
+        let news = sim.create_channel("#news".to_string()).unwrap();
+        assert!(sim.create_channel("News".to_string()).is_err());
+        sim.switch_channel(news).unwrap();
+        sim.send_message("in news").unwrap();
+        assert_eq!(1, sim.return_current_place_messages().len());
+        assert!(sim.return_current_place_messages()[0].ends_with("in news"));
+        // Read only for Basic
+        sim.set_channel_override(news, OverrideTarget::Role(place::roles::BASIC_ROLE_ID), Permissions::empty(), Permissions::SEND_MESSAGES).unwrap();
+        assert!(sim.return_current_user_perms().has(Permissions::SEND_MESSAGES));
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        sim.change_place(place_id);
+        assert_eq!(place::GENERAL_CHANNEL_ID, sim.return_current_channel());
+        assert!(sim.return_current_place_messages().iter().any(|m| m.ends_with("in general")));
+        sim.switch_channel(news).unwrap();
+        assert!(!sim.return_current_user_perms().has(Permissions::SEND_MESSAGES));
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().has_channel_perm(other, place::GENERAL_CHANNEL_ID, Permissions::SEND_MESSAGES));
+        assert!(sim.delete_channel(news).is_err());
+        sim.log_off();
+
+        // Deleting the channel sends its viewers back to general
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.delete_channel(place::GENERAL_CHANNEL_ID).is_err());
+        sim.delete_channel(news).unwrap();
+        assert_eq!(place::GENERAL_CHANNEL_ID, sim.get_user_by_id(other).unwrap().borrow().channel);
+    }
+
+    #[test]
+    fn check_channel_overrides() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let third = sim.create_user("third".to_string(), "0000".to_string());
+        let fourth = sim.create_user("fourth".to_string(), "1111".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+        let place = sim.get_place_by_id(place_id).unwrap();
+        for id in [other, third, fourth] {
+            place.borrow_mut().add_user(sim.get_user_by_id(id).unwrap());
+        }
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        let staff = sim.create_channel("staff".to_string()).unwrap();
+        let mods = sim.create_role("Mods".to_string(), RolePerms::new(Permissions::empty(), Permissions::empty(), 5)).unwrap();
+        sim.grant_role(other, mods).unwrap();
+        // Only Mods can see staff, and third gets in on their own
+        sim.set_channel_override(staff, OverrideTarget::Role(place::roles::BASIC_ROLE_ID), Permissions::empty(), Permissions::READ_HISTORY).unwrap();
+        sim.set_channel_override(staff, OverrideTarget::Role(mods), Permissions::READ_HISTORY, Permissions::empty()).unwrap();
+        sim.set_channel_override(staff, OverrideTarget::User(third), Permissions::READ_HISTORY, Permissions::SEND_MESSAGES).unwrap();
+        assert!(sim.set_channel_override(staff, OverrideTarget::User(99), Permissions::empty(), Permissions::empty()).is_err());
+
+        let place = place.borrow();
+        assert!(!place.has_channel_perm(fourth, staff, Permissions::READ_HISTORY));
+        assert!(place.has_channel_perm(other, staff, Permissions::READ_HISTORY));
+        assert!(place.has_channel_perm(third, staff, Permissions::READ_HISTORY));
+        assert!(!place.has_channel_perm(third, staff, Permissions::SEND_MESSAGES));
+        // Place level stays as it was
+        assert!(place.has_perm(third, Permissions::SEND_MESSAGES));
+        let sources = place.explain_perms(third, Some(staff));
+        let send = sources.iter().find(|s| s.perm == Permissions::SEND_MESSAGES).unwrap();
+        assert_eq!(Some("User override in #staff".to_string()), send.role);
+        drop(place);
+        sim.log_off();
+
+        sim.log_in("third".to_string(), "0000".to_string()).unwrap();
+        sim.change_place(place_id);
+        sim.switch_channel(staff).unwrap();
+        assert!(sim.send_message("hi").is_err());
+    }
+
+    #[test]
+    fn check_leave_place() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        assert!(sim.leave_place(place_id).is_err());
+        sim.change_place(place_id);
+        sim.change_place(0);
+        // Joined but looking somewhere else
+        assert!(sim.get_user_by_id(other).unwrap().borrow().has_joined(place_id));
+        assert_eq!(vec![0, place_id], sim.return_joined_places().iter().map(|p| p.0.place_id).collect::<Vec<u64>>());
+        sim.change_place(place_id);
+        sim.leave_place(place_id).unwrap();
+        assert_eq!(0, sim.return_current_place().borrow().id());
+        assert!(!sim.get_user_by_id(other).unwrap().borrow().has_joined(place_id));
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().find_user_by_id(other).is_none());
+        assert!(sim.leave_place(0).is_err());
+        sim.log_off();
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.leave_place(place_id).is_err());
+        sim.change_place(place_id);
+        let history = sim.return_current_place_messages();
+        assert!(history.iter().any(|m| m.starts_with("* other joined")));
+        assert!(history.last().unwrap().starts_with("* other left"));
+    }
+
+    #[test]
+    fn check_joined_place_list() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let first = sim.create_place("First".to_string(), owner);
+        let second = sim.create_place("Second".to_string(), owner);
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert_eq!(vec![0, first, second], sim.return_joined_places().iter().map(|p| p.0.place_id).collect::<Vec<u64>>());
+        sim.move_joined_place(3, 1).unwrap();
+        sim.set_favourite_place(1, true).unwrap();
+        assert!(sim.move_joined_place(0, 1).is_err());
+        assert_eq!(Ok(second), sim.switch_to_joined_place(1));
+        assert_eq!(second, sim.return_current_place().borrow().id());
+        assert!(sim.switch_to_joined_place(4).is_err());
+        sim.log_off();
+
+        // Still there on the next login
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        let list = sim.return_joined_places();
+        assert_eq!(second, list[0].0.place_id);
+        assert!(list[0].0.favourite);
+        assert!(list[0].0.last_visited.is_some());
+        assert!(list[2].0.last_visited.is_none());
+    }
+
+    #[test]
+    fn check_profile() {
+        let mut sim = Simulation::new();
+        sim.create_user("test".to_string(), "1234".to_string());
+        sim.create_user("other".to_string(), "1234".to_string());
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+
+        let user = sim.return_current_user().unwrap();
+        assert_eq!("test", user.borrow().name());
+        assert!(sim.edit_profile(ProfileField::DisplayName, "x").is_err());
+        assert!(sim.edit_profile(ProfileField::DisplayName, "OTHER").is_err());
+        sim.edit_profile(ProfileField::DisplayName, "Tester").unwrap();
+        sim.edit_profile(ProfileField::Status, "busy").unwrap();
+        assert_eq!("User Tester, login test [online] - busy", user.borrow().to_string());
+        assert!(Rc::ptr_eq(&user, &sim.find_user("tester").unwrap()));
+
+        sim.log_off();
+        sim.log_in("other".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.edit_profile(ProfileField::DisplayName, "tester").is_err());
+        // Clearing falls back to the login
+        sim.log_off();
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.edit_profile(ProfileField::DisplayName, "").unwrap();
+        assert_eq!("test", user.borrow().name());
+        // Numbers would read as user ids
+        assert!(sim.edit_profile(ProfileField::DisplayName, "42").is_err());
+        sim.edit_profile(ProfileField::DisplayName, "Shown").unwrap();
+        sim.log_off();
+
+        // New logins can't match an existing login or display name
+        let register = |sim: &mut Simulation, login: &str| sim.register_user(&io::LoginData::new(login.to_string(), "password".to_string()), None);
+        assert!(register(&mut sim, "other").is_err());
+        assert!(register(&mut sim, "shown").is_err());
+        assert!(register(&mut sim, "1").is_err());
+        assert!(register(&mut sim, "fresh").is_ok());
+    }
+
+    #[test]
+    fn check_friends() {
+        let mut sim = Simulation::new();
+        let first = sim.create_user("first".to_string(), "1234".to_string());
+        let second = sim.create_user("second".to_string(), "1234".to_string());
+        let third = sim.create_user("third".to_string(), "1234".to_string());
+
+        sim.log_in("first".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.send_friend_request(first).is_err());
+        sim.send_friend_request(second).unwrap();
+        assert!(sim.send_friend_request(second).is_err());
+        sim.send_friend_request(third).unwrap();
+        sim.cancel_friend_request(third).unwrap();
+        assert_eq!(1, sim.return_friend_requests().1.len());
+        sim.log_off();
+
+        sim.log_in("second".to_string(), "1234".to_string()).unwrap();
+        assert_eq!(first, sim.return_friend_requests().0[0].from);
+        sim.accept_friend_request(first).unwrap();
+        let friends = sim.return_friends();
+        assert_eq!(1, friends.len());
+        assert_eq!("first", friends[0].name);
+        assert_eq!(Presence::Offline, friends[0].presence);
+        assert_eq!(Some("Default".to_string()), friends[0].place);
+        sim.log_off();
+
+        // Where they are stays private unless the place is public or shared
+        sim.log_in("first".to_string(), "1234".to_string()).unwrap();
+        let place_id = sim.create_place("Secret".to_string(), first);
+        sim.set_place_visibility(place_id, Visibility::Hidden).unwrap();
+        sim.change_place(place_id);
+        sim.log_off();
+        sim.log_in("second".to_string(), "1234".to_string()).unwrap();
+        assert_eq!(None, sim.return_friends()[0].place);
+        sim.get_place_by_id(place_id).unwrap().borrow_mut().add_user(sim.get_user_by_id(second).unwrap());
+        assert_eq!(Some("Secret".to_string()), sim.return_friends()[0].place);
+        sim.log_off();
+
+        sim.log_in("third".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.decline_friend_request(first).is_err());
+        sim.log_off();
+
+        sim.log_in("first".to_string(), "1234".to_string()).unwrap();
+        assert_eq!(second, sim.return_friends()[0].id);
+        sim.remove_friend(second).unwrap();
+        assert!(sim.return_friends().is_empty());
+        assert!(!sim.get_user_by_id(second).unwrap().borrow().data.is_friend(first));
+    }
+
+    #[test]
+    fn check_blocking() {
+        let mut sim = Simulation::new();
+        let first = sim.create_user("first".to_string(), "1234".to_string());
+        let second = sim.create_user("second".to_string(), "1234".to_string());
+
+        sim.log_in("second".to_string(), "1234".to_string()).unwrap();
+        sim.send_message("one").unwrap();
+        sim.send_message("two").unwrap();
+        sim.log_off();
+
+        sim.log_in("first".to_string(), "1234".to_string()).unwrap();
+        sim.send_friend_request(second).unwrap();
+        sim.block_user(second).unwrap();
+        assert!(sim.return_friend_requests().1.is_empty());
+        sim.send_message("three").unwrap();
+        let messages = sim.return_current_place_messages();
+        // Their join event and both messages
+        assert!(messages.contains(&"[3 message(s) from blocked users]".to_string()));
+        assert!(!messages.iter().any(|m| m.contains("one") || m.contains("two")));
+        assert!(messages.last().unwrap().ends_with("three"));
+        assert!(sim.send_friend_request(second).is_err());
+        sim.log_off();
+
+        sim.log_in("second".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.send_friend_request(first).is_err());
+        assert!(sim.return_current_place_messages().iter().any(|m| m.ends_with("one")));
+        sim.log_off();
+
+        sim.log_in("first".to_string(), "1234".to_string()).unwrap();
+        sim.unblock_user(second).unwrap();
+        assert!(sim.unblock_user(second).is_err());
+        assert!(sim.return_current_place_messages().iter().any(|m| m.ends_with("two")));
+    }
+
+    #[test]
+    fn check_presence() {
+        let mut sim = Simulation::new();
+        sim.set_admin_password("***".to_string()).unwrap();
+        let id = sim.create_user("test".to_string(), "1234".to_string());
+        let user = sim.get_user_by_id(id).unwrap();
+        assert_eq!(Presence::Offline, user.borrow().presence());
+        assert!(user.borrow().last_seen().is_none());
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert_eq!(Presence::Online, user.borrow().presence());
+        let seen = user.borrow().last_seen().unwrap();
+        assert_eq!(Presence::Idle, user.borrow().presence_at(seen + user::IDLE_AFTER));
+        sim.send_message("hi").unwrap();
+        assert!(user.borrow().last_seen().unwrap() >= seen);
+
+        sim.set_presence(Presence::DoNotDisturb).unwrap();
+        assert_eq!(Presence::DoNotDisturb, user.borrow().presence_at(seen + user::IDLE_AFTER));
+        assert!(sim.set_presence(Presence::Idle).is_err());
+
+        // Logging in as someone else logs the previous user off
+        sim.log_in("Admin".to_string(), "***".to_string()).unwrap();
+        sim.change_password("***", "admin password").unwrap();
+        assert_eq!(Presence::Offline, user.borrow().presence());
+        sim.log_off();
+        assert_eq!(Presence::Offline, sim.get_user_by_id(0).unwrap().borrow().presence());
+    }
+
+    #[test]
+    fn check_delete_account() {
+        let mut sim = Simulation::new();
+        let id = sim.create_user("test".to_string(), "1234".to_string());
+        let friend = sim.create_user("friend".to_string(), "1234".to_string());
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        let place_id = sim.create_place("Mine".to_string(), id);
+        sim.send_message("bye").unwrap();
+        sim.send_friend_request(friend).unwrap();
+        assert!(sim.delete_account("1234").is_err());
+        sim.delete_place(place_id).unwrap();
+        // Banned members are erased too
+        sim.get_place_by_id(0).unwrap().borrow_mut().ban_user(id);
+        assert!(sim.delete_account("wrong").is_err());
+        sim.delete_account("1234").unwrap();
+
+        assert!(!sim.logged());
+        assert!(sim.get_user_by_id(id).is_err());
+        assert!(sim.get_place_by_id(0).unwrap().borrow().find_user_by_id(id).is_none());
+        sim.log_in("friend".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.return_friend_requests().0.is_empty());
+        assert!(sim.return_current_place_messages().iter().any(|m| m.starts_with(place::DELETED_USER_NAME) && m.ends_with("bye")));
+
+        // The login is free again and the id is not reused
+        let new_id = sim.create_user("test".to_string(), "abcd".to_string());
+        assert_ne!(id, new_id);
+        assert!(sim.log_in("test".to_string(), "abcd".to_string()).is_ok());
+    }
+
+    #[test]
+    fn check_suspension() {
+        let mut sim = Simulation::new();
+        sim.set_admin_password("***".to_string()).unwrap();
+        let id = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "1234".to_string());
+        assert!(sim.is_operator(0));
+        assert!(!sim.is_operator(id));
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.suspend_user(other, "spam".to_string(), None).is_err());
+        sim.log_off();
+
+        sim.log_in("Admin".to_string(), "***".to_string()).unwrap();
+        // Not before the seeded password is changed
+        assert_eq!(Err(PASSWORD_CHANGE_REQUIRED), sim.suspend_user(id, "spam".to_string(), None));
+        sim.change_password("***", "admin password").unwrap();
+        assert!(sim.suspend_user(0, "oops".to_string(), None).is_err());
+        sim.suspend_user(id, "spam".to_string(), None).unwrap();
+        // Already over
+        sim.suspend_user(other, "short".to_string(), Some(Duration::ZERO)).unwrap();
+        sim.log_off();
+
+        assert_eq!(Err(ACCOUNT_SUSPENDED), sim.log_in("test".to_string(), "1234".to_string()));
+        assert_eq!("spam", sim.login_suspension().unwrap().reason);
+        // Wrong password doesn't reveal the suspension
+        assert_ne!(Err(ACCOUNT_SUSPENDED), sim.log_in("test".to_string(), "bad".to_string()));
+        assert!(sim.login_suspension().is_none());
+        assert!(sim.log_in("other".to_string(), "1234".to_string()).is_ok());
+        assert!(sim.unsuspend_user(id).is_err());
+
+        sim.log_in("Admin".to_string(), "admin password".to_string()).unwrap();
+        sim.unsuspend_user(id).unwrap();
+        sim.set_operator(id, true).unwrap();
+        assert!(sim.set_operator(0, false).is_err());
+        assert!(sim.log_in("test".to_string(), "1234".to_string()).is_ok());
+        assert!(sim.is_operator(id));
+    }
+
+    #[test]
+    fn check_admin_bootstrap() {
+        let mut sim = Simulation::new();
+        assert!(!sim.is_admin_enabled());
+        assert!(sim.log_in("Admin".to_string(), "***".to_string()).is_err());
+        assert!(sim.log_in("Admin".to_string(), String::new()).is_err());
+
+        sim.set_admin_password("seeded".to_string()).unwrap();
+        assert!(sim.set_admin_password("other".to_string()).is_err());
+        sim.log_in("Admin".to_string(), "seeded".to_string()).unwrap();
+        assert!(sim.password_change_required());
+        assert!(sim.change_password("seeded", "short").is_err());
+        assert!(sim.change_password("wrong", "long enough").is_err());
+        sim.change_password("seeded", "long enough").unwrap();
+        assert!(!sim.password_change_required());
+        sim.log_off();
+        assert!(sim.log_in("Admin".to_string(), "long enough".to_string()).is_ok());
+    }
+
+    #[test]
+    fn check_login_throttling() {
+        let mut sim = Simulation::new();
+        sim.set_admin_password("***".to_string()).unwrap();
+        sim.create_user("test".to_string(), "1234".to_string());
+        // Same answer for a missing login and a wrong password
+        assert_eq!(Err(LOGIN_FAILED), sim.log_in("nobody".to_string(), "1234".to_string()));
+        assert_eq!(Err(LOGIN_FAILED), sim.log_in("test".to_string(), "bad".to_string()));
+
+        for _ in 1..auth::FREE_ATTEMPTS {
+            sim.log_in_from("test".to_string(), "bad".to_string(), "remote").unwrap_err();
+        }
+        // Right password is refused while backing off
+        assert_eq!(Err(TOO_MANY_ATTEMPTS), sim.log_in_from("test".to_string(), "1234".to_string(), "remote"));
+        // Lockout follows if the attempts keep failing
+        sim.login_throttle.record_failure("test", "remote", SystemTime::now());
+        sim.login_throttle.record_failure("test", "remote", SystemTime::now());
+
+        sim.log_in("Admin".to_string(), "***".to_string()).unwrap();
+        sim.change_password("***", "admin password").unwrap();
+        let events = sim.return_lockout_events().unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!("test", events[0].key);
+        sim.unlock_login("test").unwrap();
+        assert!(sim.log_in("test".to_string(), "1234".to_string()).is_ok());
+        assert!(sim.return_lockout_events().is_err());
+    }
+
+    #[test]
+    fn check_two_factor_login() {
+        let mut sim = Simulation::new();
+        let id = sim.create_user("test".to_string(), "1234".to_string());
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        assert!(sim.begin_totp_enrollment().is_err());
+        sim.create_place("Mine".to_string(), id);
+
+        let uri = sim.begin_totp_enrollment().unwrap();
+        assert!(uri.starts_with("otpauth://totp/nas:test?secret="));
+        let user = sim.return_current_user().unwrap();
+        let totp = user.borrow().two_factor().unwrap().totp().clone();
+        assert!(sim.confirm_totp_enrollment("000000x").is_err());
+        let recovery = sim.confirm_totp_enrollment(&totp.code_at(SystemTime::now())).unwrap();
+        sim.log_off();
+
+        assert_eq!(Err(SECOND_FACTOR_REQUIRED), sim.log_in("test".to_string(), "1234".to_string()));
+        assert!(!sim.logged());
+        assert!(sim.is_second_factor_pending());
+        assert_eq!(Err(INVALID_CODE), sim.complete_login("12345"));
+        // Recovery codes work once
+        sim.complete_login(&recovery[0]).unwrap();
+        assert!(sim.logged());
+        sim.log_off();
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap_err();
+        assert_eq!(Err(INVALID_CODE), sim.complete_login(&recovery[0]));
+        // The enrollment code was already used, so a fresh one comes from the next step
+        let next = totp.code_at(SystemTime::now() + Duration::from_secs(auth::TOTP_STEP));
+        sim.complete_login(&next).unwrap();
+        sim.disable_two_factor(&recovery[1]).unwrap();
+        sim.log_off();
+        assert!(sim.log_in("test".to_string(), "1234".to_string()).is_ok());
+    }
+
+    #[derive(Debug)]
+    struct BrokenTransport;
+
+    impl MailTransport for BrokenTransport {
+        fn send(&self, _mail: &Mail) -> Result<(), &'static str> {
+            Err("Mail server is down")
+        }
+    }
+
+    #[test]
+    fn check_failed_registration_mail() {
+        let mut sim = Simulation::new();
+        sim.set_mail_transport(Box::new(BrokenTransport));
+        let data = io::LoginData::new("test".to_string(), "password".to_string());
+        assert!(sim.register_user(&data, Some("test@example.com")).is_err());
+        // Nothing was kept, so trying again with a working transport goes through
+        assert!(sim.mail_tokens.is_empty());
+        sim.set_mail_transport(Box::new(mail::MemoryOutbox::new()));
+        let id = sim.register_user(&data, Some("test@example.com")).unwrap();
+        assert_eq!(1, sim.mail_tokens.len());
+        assert_eq!(id, sim.mail_tokens[0].user_id);
+
+        // A failed reset mail looks the same as an unknown address
+        sim.get_user_by_id(id).unwrap().borrow_mut().data.email_verified = true;
+        sim.set_mail_transport(Box::new(BrokenTransport));
+        assert!(sim.request_password_reset("test@example.com").is_ok());
+        assert!(sim.request_password_reset("nobody@example.com").is_ok());
+        assert!(sim.mail_tokens.iter().all(|t| t.purpose != TokenPurpose::ResetPassword));
+    }
+
+    #[test]
+    fn check_email_flows() {
+        let mut sim = Simulation::new();
+        let outbox = mail::MemoryOutbox::new();
+        sim.set_mail_transport(Box::new(outbox.clone()));
+        let token_in = |mail: mail::Mail| mail.body.split_whitespace().find(|w| w.len() == 32).unwrap().to_string();
+
+        assert!(sim.register_user(&io::LoginData::new("test".to_string(), "password".to_string()), Some("not an address")).is_err());
+        assert!(sim.register_user(&io::LoginData::new("test".to_string(), "short".to_string()), Some("test@example.com")).is_err());
+        let id = sim.register_user(&io::LoginData::new("test".to_string(), "password".to_string()), Some("test@example.com")).unwrap();
+        assert!(sim.register_user(&io::LoginData::new("other".to_string(), "password".to_string()), Some("TEST@example.com")).is_err());
+        let user = sim.get_user_by_id(id).unwrap();
+        assert_eq!("test@example.com", user.borrow().data.email);
+        assert!(sim.register_user(&io::LoginData::new("test".to_string(), "password".to_string()), None).is_err());
+        assert!(sim.register_user(&io::LoginData::new("plain".to_string(), "password".to_string()), None).is_ok());
+
+        // Reset is silent for unverified or unknown addresses
+        sim.request_password_reset("test@example.com").unwrap();
+        sim.request_password_reset("nobody@example.com").unwrap();
+        assert_eq!(1, outbox.mails().len());
+
+        let verify = token_in(outbox.last_to("test@example.com").unwrap());
+        sim.verify_email(&verify).unwrap();
+        assert!(user.borrow().data.email_verified);
+        assert!(sim.verify_email(&verify).is_err());
+
+        sim.request_password_reset("test@example.com").unwrap();
+        let reset = token_in(outbox.last_to("test@example.com").unwrap());
+        // Only the hash of the mailed token is kept
+        assert!(sim.mail_tokens.iter().all(|t| t.token_hash != reset && t.token_hash == auth::hash_token(&reset)));
+        assert!(sim.reset_password(&verify, "new password").is_err());
+        assert!(sim.reset_password(&reset, "short").is_err());
+        sim.reset_password(&reset, "new password").unwrap();
+        assert!(sim.reset_password(&reset, "newer password").is_err());
+        assert!(sim.log_in("test".to_string(), "new password".to_string()).is_ok());
+
+        // New address has to be verified again
+        sim.set_email("new@example.com").unwrap();
+        assert!(!user.borrow().data.email_verified);
+        assert_eq!(3, outbox.mails().len());
+    }
+
+    #[test]
+    fn check_sessions() {
+        let mut sim = Simulation::new();
+        sim.set_admin_password("***".to_string()).unwrap();
+        let id = sim.create_user("test".to_string(), "password".to_string());
+        let first = sim.log_in("test".to_string(), "password".to_string()).unwrap();
+        let second = sim.log_in_from("test".to_string(), "password".to_string(), "laptop").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(2, sim.return_sessions().len());
+        assert_eq!("laptop", sim.return_sessions()[1].source);
+
+        // Logging in as someone else and coming back with the token
+        sim.log_in("Admin".to_string(), "***".to_string()).unwrap();
+        sim.resume_session(&first).unwrap();
+        assert_eq!(id, sim.return_current_user().unwrap().borrow().id());
+        assert!(sim.resume_session("made up").is_err());
+
+        // Logging off ends only this session
+        sim.log_off();
+        assert!(sim.resume_session(&first).is_err());
+        sim.resume_session(&second).unwrap();
+        let current = sim.current_session_id().unwrap();
+        assert!(sim.revoke_session(current + 100).is_err());
+
+        let third = sim.log_in("test".to_string(), "password".to_string()).unwrap();
+        sim.revoke_session(current).unwrap();
+        assert!(sim.resume_session(&second).is_err());
+        // Password change revokes everything
+        sim.change_password("password", "new password").unwrap();
+        assert!(sim.return_sessions().is_empty());
+        assert_eq!(None, sim.current_session_id());
+        assert!(sim.logged());
+        assert!(sim.resume_session(&third).is_err());
+    }
+
+    #[test]
+    fn check_join_requests() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let other = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Club".to_string(), owner);
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.set_place_visibility(place_id, Visibility::RequestToJoin).unwrap();
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        assert!(sim.change_place(place_id).is_some());
+        assert!(sim.change_place(place_id).is_some());
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().find_user_by_id(other).is_none());
+        let mine = sim.return_my_join_requests();
+        assert_eq!(1, mine.len());
+        assert_eq!(RequestStatus::Pending, mine[0].2.status);
+        // Basic members can't see the queue
+        assert!(sim.return_pending_join_requests().is_err());
+        sim.log_off();
+
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        let pending = sim.return_pending_join_requests().unwrap();
+        assert_eq!(1, pending.len());
+        sim.approve_join_request(pending[0].id, Some("Welcome!".to_string())).unwrap();
+        assert!(sim.reject_join_request(pending[0].id, None).is_err());
+        assert!(sim.get_place_by_id(place_id).unwrap().borrow().find_user_by_id(other).is_some());
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        let mine = sim.return_my_join_requests();
+        assert_eq!(RequestStatus::Approved, mine[0].2.status);
+        assert_eq!(Some("Welcome!".to_string()), mine[0].2.response);
+        // The welcome message waits for their first visit
+        let place = sim.get_place_by_id(place_id).unwrap();
+        assert!(!place.borrow().find_user_by_id(other).unwrap().welcomed);
+        assert!(sim.change_place(place_id).is_none());
+        assert!(place.borrow().find_user_by_id(other).unwrap().welcomed);
+        assert!(!place.borrow_mut().take_welcome(other));
+    }
+
+    #[test]
+    fn check_basic_perms() {
+        let mut sim = Simulation::new();
+        let admin = sim.create_user("test".to_string(), "1234".to_string());
+        let user = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Debug".to_string(), admin);
+        sim.get_place_by_id(place_id).unwrap().borrow_mut().add_user(sim.get_user_by_id(user).unwrap());
+        assert!(sim.has_perm_in_place(user, place_id, Permissions::SEND_MESSAGES));
+        assert!(!sim.has_perm_in_place(user, place_id, Permissions::BAN));
+        // Not a member at all
+        let other_place = sim.create_place("Other".to_string(), admin);
+        assert!(!sim.has_perm_in_place(user, other_place, Permissions::SEND_MESSAGES));
+    }
+
+    #[test]
+    fn check_explain_perms() {
+        let mut sim = Simulation::new();
+        let user = sim.create_user("test".to_string(), "1234".to_string());
+        let place = sim.get_default_place();
+        let muted = place.borrow_mut()
+            .create_role("Muted".to_string(), RolePerms::new(Permissions::empty(), Permissions::SEND_MESSAGES, 10))
+            .unwrap();
+        place.borrow_mut().grant_role(user, muted).unwrap();
+
+        let sources = place.borrow().explain_perms(user, None);
+        let send = sources.iter().find(|s| s.perm == Permissions::SEND_MESSAGES).unwrap();
+        assert!(!send.granted);
+        assert_eq!(Some("Muted".to_string()), send.role);
+        let read = sources.iter().find(|s| s.perm == Permissions::READ_HISTORY).unwrap();
+        assert!(read.granted);
+        assert_eq!(Some("Basic".to_string()), read.role);
+    }
+
+    #[test]
+    fn check_role_management() {
+        let mut sim = Simulation::new();
+        let admin = sim.create_user("test".to_string(), "1234".to_string());
+        let user = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Debug".to_string(), admin);
+        sim.get_place_by_id(place_id).unwrap().borrow_mut().add_user(sim.get_user_by_id(user).unwrap());
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+
+        let role = sim.create_role("Helper".to_string(), RolePerms::new(Permissions::PIN, Permissions::empty(), 5)).unwrap();
+        // Same priority, different role, both kept
+        let other = sim.create_role("Reader".to_string(), RolePerms::new(Permissions::READ_HISTORY, Permissions::empty(), 5)).unwrap();
+        assert_ne!(role, other);
+        assert!(sim.create_role("helper".to_string(), RolePerms::new_basic()).is_err());
+
+        sim.grant_role(user, role).unwrap();
+        assert!(sim.has_perm_in_place(user, place_id, Permissions::PIN));
+
+        // Editing the role changes it for everyone who has it
+        sim.edit_role_perms(role, RolePerms::new(Permissions::empty(), Permissions::PIN, 5)).unwrap();
+        assert!(!sim.has_perm_in_place(user, place_id, Permissions::PIN));
+
+        sim.rename_role(role, "Pinner".to_string()).unwrap();
+        assert_eq!("Pinner", sim.return_current_place().borrow().find_role_by_id(role).unwrap().name);
+
+        sim.revoke_role(user, role).unwrap();
+        assert!(sim.revoke_role(user, role).is_err());
+        sim.grant_role(user, role).unwrap();
+        sim.delete_role(role).unwrap();
+        assert!(!sim.return_current_place().borrow().find_user_by_id(user).unwrap().return_roles().contains(&role));
+        assert!(sim.delete_role(place::roles::BASIC_ROLE_ID).is_err());
+
+        // Basic members can't manage roles
+        sim.log_off();
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        sim.change_place(place_id);
+        assert!(sim.create_role("Nope".to_string(), RolePerms::new_basic()).is_err());
+    }
+
+    #[test]
+    fn check_role_hierarchy() {
+        let mut sim = Simulation::new();
+        let owner = sim.create_user("test".to_string(), "1234".to_string());
+        let user = sim.create_user("other".to_string(), "4321".to_string());
+        let place_id = sim.create_place("Debug".to_string(), owner);
+        sim.get_place_by_id(place_id).unwrap().borrow_mut().add_user(sim.get_user_by_id(user).unwrap());
+        sim.log_in("test".to_string(), "1234".to_string()).unwrap();
+        sim.change_place(place_id);
+        let manager = sim.create_role("Manager".to_string(), RolePerms::new(Permissions::basic() | Permissions::MANAGE_ROLES, Permissions::empty(), 10)).unwrap();
+        let banner = sim.create_role("Banner".to_string(), RolePerms::new(Permissions::BAN, Permissions::empty(), 5)).unwrap();
+        sim.grant_role(user, manager).unwrap();
+        // Built-in roles stay as they are, even for the owner
+        assert!(sim.edit_role_perms(place::roles::BASIC_ROLE_ID, RolePerms::new(Permissions::all(), Permissions::empty(), 0)).is_err());
+        sim.log_off();
+
+        sim.log_in("other".to_string(), "4321".to_string()).unwrap();
+        sim.change_place(place_id);
+        // Nothing at or above their own role, nothing they don't hold
+        assert!(sim.create_role("Peer".to_string(), RolePerms::new(Permissions::basic(), Permissions::empty(), 10)).is_err());
+        assert!(sim.create_role("Boss".to_string(), RolePerms::new(Permissions::basic(), Permissions::empty(), 20)).is_err());
+        assert!(sim.create_role("Ban".to_string(), RolePerms::new(Permissions::BAN, Permissions::empty(), 5)).is_err());
+        assert!(sim.edit_role_perms(manager, RolePerms::new(Permissions::all(), Permissions::empty(), 10)).is_err());
+        assert!(sim.grant_role(user, place::roles::ADMIN_ROLE_ID).is_err());
+        assert!(sim.grant_role(user, banner).is_err());
+        assert!(sim.revoke_role(owner, place::roles::ADMIN_ROLE_ID).is_err());
+        assert!(sim.delete_role(manager).is_err());
+        // Overrides can't lift them either
+        assert!(sim.set_channel_override(place::GENERAL_CHANNEL_ID, OverrideTarget::User(user), Permissions::all(), Permissions::empty()).is_err());
+        assert!(sim.set_channel_override(place::GENERAL_CHANNEL_ID, OverrideTarget::Role(manager), Permissions::all(), Permissions::empty()).is_err());
+        assert!(sim.set_channel_override(place::GENERAL_CHANNEL_ID, OverrideTarget::User(owner), Permissions::empty(), Permissions::all()).is_err());
+        assert!(!sim.has_perm_in_place(user, place_id, Permissions::BAN));
+
+        // Below them is fine
+        let helper = sim.create_role("Helper".to_string(), RolePerms::new(Permissions::basic(), Permissions::empty(), 5)).unwrap();
+        sim.grant_role(user, helper).unwrap();
+        sim.set_channel_override(place::GENERAL_CHANNEL_ID, OverrideTarget::Role(helper), Permissions::empty(), Permissions::SEND_MESSAGES).unwrap();
     }
 }
\ No newline at end of file
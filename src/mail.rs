@@ -0,0 +1,207 @@
+use std::{cell::RefCell, fmt::Debug, fs::OpenOptions, io::{BufRead, BufReader, Write}, net::TcpStream, path::PathBuf, rc::Rc, time::Duration};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+impl Mail {
+    pub fn new(to: &str, subject: &str, body: String) -> Mail {
+        Mail {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body
+        }
+    }
+}
+
+// Where the Simulation sends its mails
+pub trait MailTransport: Debug {
+    fn send(&self, mail: &Mail) -> Result<(), &'static str>;
+}
+
+// Keeps mails in memory, clones share the same outbox
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOutbox {
+    mails: Rc<RefCell<Vec<Mail>>>
+}
+
+impl MemoryOutbox {
+    pub fn new() -> MemoryOutbox {
+        MemoryOutbox::default()
+    }
+
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails.borrow().clone()
+    }
+
+    pub fn last_to(&self, to: &str) -> Option<Mail> {
+        self.mails.borrow().iter().rev().find(|m| m.to == to).cloned()
+    }
+}
+
+impl MailTransport for MemoryOutbox {
+    fn send(&self, mail: &Mail) -> Result<(), &'static str> {
+        self.mails.borrow_mut().push(mail.clone());
+        Ok(())
+    }
+}
+
+// Appends mails to a text file
+#[derive(Debug)]
+pub struct FileOutbox {
+    path: PathBuf
+}
+
+impl FileOutbox {
+    pub fn new(path: impl Into<PathBuf>) -> FileOutbox {
+        FileOutbox { path: path.into() }
+    }
+}
+
+impl MailTransport for FileOutbox {
+    fn send(&self, mail: &Mail) -> Result<(), &'static str> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|_| "Cannot open the outbox file")?;
+        writeln!(file, "To: {}\nSubject: {}\n\n{}\n-----", mail.to, mail.subject, mail.body)
+            .map_err(|_| "Cannot write to the outbox file")
+    }
+}
+
+// Plain SMTP without TLS or auth, meant for a local relay
+#[derive(Debug)]
+pub struct SmtpTransport {
+    // host:port
+    address: String,
+    from: String,
+    timeout: Duration
+}
+
+impl SmtpTransport {
+    pub fn new(address: &str, from: &str) -> SmtpTransport {
+        SmtpTransport {
+            address: address.to_string(),
+            from: from.to_string(),
+            timeout: Duration::from_secs(10)
+        }
+    }
+
+    // Reads a possibly multi-line reply and checks its code class
+    fn expect(reader: &mut impl BufRead, class: char) -> Result<(), &'static str> {
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).map_err(|_| "SMTP server stopped responding")? == 0 {
+                return Err("SMTP server closed the connection");
+            }
+            if !line.starts_with(class) {return Err("SMTP server refused the mail");}
+            // "250-" continues, "250 " ends the reply
+            if line.as_bytes().get(3) != Some(&b'-') {return Ok(());}
+        }
+    }
+
+    fn command(stream: &mut TcpStream, reader: &mut impl BufRead, line: &str, class: char) -> Result<(), &'static str> {
+        write!(stream, "{line}\r\n").map_err(|_| "Cannot write to the SMTP server")?;
+        Self::expect(reader, class)
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&self, mail: &Mail) -> Result<(), &'static str> {
+        let mut stream = TcpStream::connect(&self.address).map_err(|_| "Cannot connect to the SMTP server")?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(|_| "Cannot set SMTP timeout")?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(|_| "Cannot read from the SMTP server")?);
+        Self::expect(&mut reader, '2')?;
+        Self::command(&mut stream, &mut reader, "HELO nas", '2')?;
+        Self::command(&mut stream, &mut reader, &format!("MAIL FROM:<{}>", self.from), '2')?;
+        Self::command(&mut stream, &mut reader, &format!("RCPT TO:<{}>", mail.to), '2')?;
+        Self::command(&mut stream, &mut reader, "DATA", '3')?;
+        let mut data = format!("From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\n\r\n", self.from, mail.to, mail.subject);
+        for line in mail.body.lines() {
+            // Lines starting with a dot are doubled so they don't end the data
+            if line.starts_with('.') {data.push('.');}
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+        Self::command(&mut stream, &mut reader, &data, '2')?;
+        Self::command(&mut stream, &mut reader, "QUIT", '2')
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn check_memory_outbox() {
+        let outbox = MemoryOutbox::new();
+        let transport: Box<dyn MailTransport> = Box::new(outbox.clone());
+        transport.send(&Mail::new("a@b.c", "Hi", "Body".to_string())).unwrap();
+        assert_eq!("Hi", outbox.last_to("a@b.c").unwrap().subject);
+        assert!(outbox.last_to("x@b.c").is_none());
+    }
+
+    #[test]
+    fn check_smtp_transport() {
+        // Local stand-in server recording what it receives
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = vec![];
+            write!(stream, "220 localhost ready\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {break;}
+                let line = line.trim_end().to_string();
+                received.push(line.clone());
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        write!(stream, "250 queued\r\n").unwrap();
+                    }
+                    continue;
+                }
+                match line.as_str() {
+                    "DATA" => {
+                        in_data = true;
+                        write!(stream, "354 go ahead\r\n").unwrap();
+                    }
+                    "QUIT" => {
+                        write!(stream, "221 bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => write!(stream, "250-ok\r\n250 ok\r\n").unwrap(),
+                }
+            }
+            received
+        });
+
+        let transport = SmtpTransport::new(&address, "nas@localhost");
+        transport.send(&Mail::new("user@example.com", "Token", "Your token\n.hidden dot".to_string())).unwrap();
+        let received = server.join().unwrap();
+        assert_eq!("HELO nas", received[0]);
+        assert!(received.contains(&"RCPT TO:<user@example.com>".to_string()));
+        assert!(received.contains(&"Subject: Token".to_string()));
+        assert!(received.contains(&"..hidden dot".to_string()));
+        assert_eq!("QUIT", received.last().unwrap());
+    }
+
+    #[test]
+    fn check_smtp_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            write!(stream, "554 no service\r\n").unwrap();
+        });
+        let transport = SmtpTransport::new(&address, "nas@localhost");
+        assert!(transport.send(&Mail::new("user@example.com", "Hi", String::new())).is_err());
+        server.join().unwrap();
+    }
+}
@@ -11,7 +11,7 @@ To Do:
 8. Desktop app
 9. BANNING - done
 10. More place functions (see members, see messages, see options) - done done notDone
-11. User and place config
+11. User and place config - notDone done
 12. Default, empty current place, behaviour
 13. Responsive text menu
 14. PlaceMessage date formatting
@@ -19,39 +19,66 @@ To Do:
 */
 
 
-use nas::place::roles;
-use nas::place::roles::RoleTrait;
+use nas::place::{roles, OverrideTarget, PlaceSort};
+use nas::place::roles::Permissions;
 use nas::Simulation;
+use nas::user::Presence;
+use chrono::{DateTime, Local};
 use nas::io;
+use nas::commands;
+use nas::mail;
+
+// host:port of an SMTP relay for outgoing mails
+const SMTP_ENV: &str = "NAS_SMTP";
+const OUTBOX_FILE: &str = "outbox.txt";
 
 fn main() {
     // Start simulation
-    let mut sim = Simulation::new();
+    let mut sim = Simulation::from_env();
+    if !sim.is_admin_enabled() {
+        println!("The {} account is disabled. Set {} to give it a first password.", nas::ADMIN_LOGIN, nas::ADMIN_PASSWORD_ENV);
+    }
+
+    // Mails go to a local SMTP relay if one is given, otherwise to a file
+    match std::env::var(SMTP_ENV) {
+        Ok(address) if !address.is_empty() => sim.set_mail_transport(Box::new(mail::SmtpTransport::new(&address, "nas@localhost"))),
+        _ => {
+            println!("Mails are written to {OUTBOX_FILE}. Set {SMTP_ENV} to send them over SMTP.");
+            sim.set_mail_transport(Box::new(mail::FileOutbox::new(OUTBOX_FILE)));
+        }
+    }
 
     // Build first user
-    let login_tuple = io::get_name_and_pass_from_console(true);
-    sim.create_user(login_tuple.login(), login_tuple.password());
-    let user = match sim.get_user_by_id(1){
+    let user_id = loop {
+        let login_data = io::get_name_and_pass_from_console(true);
+        let email = io::ask("E-mail (leave empty to skip):");
+        let email = if email.is_empty() {None} else {Some(email.as_str())};
+        match sim.register_user(&login_data, email) {
+            Ok(id) => break id,
+            Err(err) => println!("{err}"),
+        }
+    };
+    let user = match sim.get_user_by_id(user_id){
         Ok(x) => x,
         Err(err) => panic!("{err}")
     };
     // Build first place
-    let place_string = io::get_place_creation_data_from_console();
-    sim.create_place(place_string, user.borrow().id());
-    let _place = match sim.get_place_by_id(0){
+    let place_data = io::get_place_creation_data_from_console();
+    // create_place borrows the owner mutably, so don't hold the user borrowed
+    let owner_id = user.borrow().id();
+    let place_id = sim.create_place(place_data.name(), owner_id);
+    let place = match sim.get_place_by_id(place_id){
         Ok(x) => x,
         Err(err) => panic!("{err}")
     };
+    let mut settings = place.borrow().settings().clone();
+    settings.description = place_data.description();
+    place.borrow_mut().update_settings(settings).expect("Fresh place should take its own settings");
 
-    // Creation of some users
-    sim.create_user("Madman".to_string(), "Jonni".to_string());
-    sim.create_user("Femboy".to_string(), "Piofli".to_string());
-    sim.create_user("The Forgotten One".to_string(), "Diat".to_string());
-
-    // Create another place
-    sim.create_place("Debug".to_string(), 2);
-
-
+    // Demo users and places only when asked for
+    if std::env::args().any(|arg| arg == "--demo") {
+        seed_demo(&mut sim);
+    }
 
     // App loop
     'app: loop {
@@ -61,14 +88,47 @@ fn main() {
         if !logged {
             let login_tuple = io::get_name_and_pass_from_console(false);
             match sim.log_in(login_tuple.login(), login_tuple.password()) {
-                Ok(s) => {println!("{s}")},
+                Ok(_) => {println!("Logged in successfuly.")},
+                Err(nas::SECOND_FACTOR_REQUIRED) => {
+                    println!("{}", nas::SECOND_FACTOR_REQUIRED);
+                    while sim.is_second_factor_pending() && !sim.logged() {
+                        match sim.complete_login(&io::ask("Code:")) {
+                            Ok(_) => println!("Logged in successfuly."),
+                            Err(nas::INVALID_CODE) => println!("{}", nas::INVALID_CODE),
+                            Err(err) => {
+                                println!("{err}");
+                                break;
+                            }
+                        }
+                    }
+                }
                 Err(err) => {
-                    println!("{err}\n\n");
+                    println!("{err}");
+                    if err == nas::ACCOUNT_SUSPENDED {
+                        if let Some(suspension) = sim.login_suspension() {
+                            println!("{suspension}");
+                        }
+                    }
+                    else if io::ask("Forgot your password? Type 'reset' to get a reset mail, or press enter.") == "reset" {
+                        reset_password(&mut sim);
+                    }
+                    println!("\n");
                     continue 'app;
                 }
             };
         }
 
+        // Seeded passwords have to be changed before anything else
+        while sim.password_change_required() {
+            println!("You need to set a new password.");
+            let old = io::read_password("Current password:");
+            let new = io::get_new_password_from_console();
+            match sim.change_password(&old, &new) {
+                Ok(()) => println!("Password changed"),
+                Err(err) => println!("{err}"),
+            }
+        }
+
         // Pre menu logic
         let cur_user = match sim.return_current_user() {
             Some(x) => x,
@@ -76,10 +136,9 @@ fn main() {
         };
         let cur_place = sim.return_current_place();
 
-        let admin: bool = sim.is_admin(cur_user.borrow().id());
-
         // Load perms
         let perms = sim.return_current_user_perms();
+        let admin = sim.is_admin(cur_user.borrow().id()) || perms.has(Permissions::BAN) || perms.has(Permissions::MUTE) || perms.has(Permissions::MANAGE_ROLES);
 
         // Check if banned
         if cur_user.borrow().place.borrow().is_banned(cur_user.borrow().id()) {
@@ -88,19 +147,54 @@ fn main() {
         }
 
         // Menu what to do
+        if let Some(channel) = cur_place.borrow().find_channel_by_id(sim.return_current_channel()) {
+            println!("{} #{}", cur_place.borrow().name, channel.name);
+            if !channel.topic.is_empty() {println!("{}", channel.topic);}
+        }
+        if !cur_place.borrow().settings().topic.is_empty() {
+            println!("Place topic: {}", cur_place.borrow().settings().topic);
+        }
         println!("Hello {}! Please pick what you want to do:", cur_user.borrow().name());
-        println!("1. Log off\n2. Change current place\n3. Print messages");
-        println!("10. Send message in current place.\n11. Show users");
+        println!("1. Log off\n2. Change current place\n3. Print messages\n4. Join place with invite code\n5. My join requests\n6. Place directory\n7. Show channels\n8. Switch channel\n9. Leave current place");
+        println!("10. Send message in current place.\n11. Show users\n12. Show roles\n13. Explain my permissions\n14. Show place settings\n15. Delete my account\n16. Change password");
 
         // Menu for admin
         if admin {
-            println!("It seems you are an admin of this place too! Nice :>\n60. Ban user\n61. Mute user")
+            println!("It seems you are an admin of this place too! Nice :>");
+            if perms.has(Permissions::BAN) {println!("60. Ban user");}
+            if perms.has(Permissions::MANAGE_ROLES) {println!("61. Create mute role");}
+            if perms.has(Permissions::MUTE) {println!("62. Mute user");}
+            if perms.has(Permissions::CREATE_INVITES) {println!("72. Create invite");}
+            if perms.has(Permissions::MANAGE_PLACE) {println!("73. Pending join requests\n74. Edit place settings\n75. Create channel");}
+            if perms.has(Permissions::MANAGE_ROLES) {
+                println!("63. Create role\n64. Rename role\n65. Edit role permissions\n66. Delete role\n67. Grant role\n68. Revoke role");
+                println!("76. Edit channel permissions");
+            }
+        }
+        // Menu for owner
+        if cur_place.borrow().is_owner(cur_user.borrow().id()) {
+            println!("You own this place.\n70. Transfer ownership\n71. Delete place");
+        }
+        if cur_user.borrow().is_operator() {
+            println!("Operator commands: /suspend <id> <minutes|-> <reason>, /unsuspend <id>, /op|deop <id>, /lockouts, /unlock <login>");
         }
+        println!("Commands: /roles, /role create|rename|perms|delete|grant|revoke, /place transfer|delete|visibility");
+        println!("          /profile [user], /profile set name|bio|pronouns|status [value], /presence online|dnd, /2fa enroll|confirm|disable|recovery");
+        println!("          /email <address>, /verify <token>|resend, /sessions, /session revoke <id>|all");
+        println!("          /friends, /friend add|accept|decline|cancel|remove <id>, /blocked, /block|unblock <id>");
+        println!("          /joined, /go <n>, /fav|unfav <n>, /move <from> <to>, /leave [place], /channels, /channel switch|create|delete|topic|perms");
+        println!("          /settings, /set <setting> <value>, /places [id|name|members|activity] [search], /join <code>, /invites, /invite create|revoke, /requests, /myrequests, /request approve|reject");
         // User input
         let mut input = String::new();
-        let user_option: usize;
         std::io::stdin().read_line(&mut input).expect("Something");
-        user_option = input.trim().parse().expect("Should be an integer");
+        if commands::is_command(&input) {
+            match commands::parse(&input).and_then(|c| commands::execute(&mut sim, c)) {
+                Ok(s) => println!("{s}"),
+                Err(err) => println!("{err}"),
+            }
+            continue 'app;
+        }
+        let user_option: usize = input.trim().parse().expect("Should be an integer");
         input.clear();
         
         match user_option {
@@ -125,9 +219,63 @@ fn main() {
                 }
             }
 
+            4 => {
+                match sim.join_with_invite(&io::ask("Give invite code.")) {
+                    Ok(id) => println!("Joined place {id}."),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            5 => {
+                match commands::execute(&mut sim, commands::Command::MyRequests) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            6 => {
+                let search = io::ask("Search by name (empty for all).");
+                let search = if search.is_empty() {None} else {Some(search)};
+                let sort = match io::ask("Sort by: 1. id 2. name 3. members 4. activity").as_str() {
+                    "2" => PlaceSort::Name,
+                    "3" => PlaceSort::Members,
+                    "4" => PlaceSort::Activity,
+                    _ => PlaceSort::Id,
+                };
+                match commands::execute(&mut sim, commands::Command::Places(sort, search)) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            7 => {
+                match commands::execute(&mut sim, commands::Command::Channels) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            8 => {
+                let channel = io::ask("Give channel id or name.");
+                match commands::execute(&mut sim, commands::Command::ChannelSwitch(channel)) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            9 => {
+                if io::ask("Type 'leave' to confirm.") != "leave" {continue 'app;}
+                match commands::execute(&mut sim, commands::Command::Leave(None)) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
             10 => {
-                if perms.can_talk {
-                    sim.send_message("It's a test message");
+                if perms.has(Permissions::SEND_MESSAGES) {
+                    if let Err(err) = sim.send_message("It's a test message") {
+                        println!("{err}");
+                    }
                 }
                 else {
                     println!("You are muted. XDDD");
@@ -137,19 +285,52 @@ fn main() {
             11 => {
                 println!("Member list of {}", cur_place.borrow().name);
                 for members in cur_place.borrow().members.iter() {
-                    if admin {
+                    if perms.has(Permissions::BAN) {
                         print!("Id: {}, ", members.user.upgrade().unwrap().borrow().id());
                     }
-                    println!("{}", members.user.upgrade().unwrap().borrow());
+                    let user = members.user.upgrade().unwrap();
+                    let user = user.borrow();
+                    match (user.presence(), user.last_seen()) {
+                        (Presence::Offline, Some(seen)) => println!("{}, last seen {}", user, DateTime::<Local>::from(seen).format("%Y-%m-%d %H:%M")),
+                        _ => println!("{}", user),
+                    }
                 }
             }
 
             12 => {
                 println!("Role list of {}", cur_place.borrow().name);
-                let mut i = 0;
                 for roles in cur_place.borrow().return_role_vec().iter() {
-                    println!("{}. {}", i, roles.name);
-                    i += 1;
+                    println!("{}. {}", roles.id(), roles);
+                }
+            }
+
+            13 => {
+                for source in sim.explain_current_user_perms() {
+                    println!("{source}");
+                }
+            }
+
+            14 => {
+                match commands::execute(&mut sim, commands::Command::Settings) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            15 => {
+                if io::ask("Type 'delete' to confirm. This cannot be undone.") != "delete" {continue 'app;}
+                match sim.delete_account(&io::read_password("Password:")) {
+                    Ok(()) => println!("Your account was deleted"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            16 => {
+                let old = io::read_password("Current password:");
+                let new = io::get_new_password_from_console();
+                match sim.change_password(&old, &new) {
+                    Ok(()) => println!("Password changed"),
+                    Err(err) => println!("{err}"),
                 }
             }
 
@@ -160,7 +341,7 @@ fn main() {
             
             // Admin stuff
             60 => {
-                if !admin {continue 'app;}
+                if !perms.has(Permissions::BAN) {continue 'app;}
                 println!("Give user id.");
                 std::io::stdin().read_line(&mut input).expect("Something");
                 let user_to_ban_id: u64 = input.trim().parse().expect("Should be an integer");
@@ -179,31 +360,192 @@ fn main() {
                 };
             }
             61 => {
-                if !admin {continue 'app;}
-                let mut role = roles::RoleTemplate::new("Muted".to_string(), 10);
-                role.update_perms(roles::RolePerms::new(false, 10));
-                cur_place.borrow_mut().add_role(role);
-                println!("Mute role added");
+                if !perms.has(Permissions::MANAGE_ROLES) {continue 'app;}
+                match sim.create_role("Muted".to_string(), roles::RolePerms::new(Permissions::empty(), Permissions::SEND_MESSAGES, 10)) {
+                    Ok(_) => println!("Mute role added"),
+                    Err(err) => println!("{err}"),
+                };
             }
 
             62 => {
-                if !admin {continue 'app;}
+                if !perms.has(Permissions::MUTE) {continue 'app;}
                 println!("Give user id.");
                 std::io::stdin().read_line(&mut input).expect("Something");
                 let user_to_mute_id: u64 = input.trim().parse().expect("Should be an integer");
-                let r = match cur_place.borrow().find_role_by_id(2){
-                    Some(role) => role.clone(),
-                    None => {continue 'app;}
+                let r = match cur_place.borrow().find_role_by_name("Muted".to_string()){
+                    Some(role) => role.id(),
+                    None => {
+                        println!("Create the mute role first.");
+                        continue 'app;
+                    }
+                };
+                // Goes through the role rank checks like any other grant
+                if let Err(err) = sim.grant_role(user_to_mute_id, r) {
+                    println!("{err}");
+                }
+            }
+
+            // Role management
+            63..=68 => {
+                if !perms.has(Permissions::MANAGE_ROLES) {continue 'app;}
+                let command = match user_option {
+                    63 => commands::Command::RoleCreate(io::ask("Give role name.")),
+                    64 => {
+                        let role: u64 = io::ask("Give role id.").parse().expect("Should be an integer");
+                        commands::Command::RoleRename(role, io::ask("Give new name."))
+                    }
+                    65 => {
+                        let role: u64 = io::ask("Give role id.").parse().expect("Should be an integer");
+                        let priority: u8 = io::ask("Give priority (0-255).").parse().expect("Should be an integer");
+                        let list = io::ask("Give permissions, e.g. +send_messages -ban");
+                        let words: Vec<&str> = list.split_whitespace().collect();
+                        let (allow, deny) = match commands::parse_perm_list(&words) {
+                            Ok(x) => x,
+                            Err(err) => {
+                                println!("{err}");
+                                continue 'app;
+                            }
+                        };
+                        commands::Command::RolePerms(role, commands::RolePermsArgs {allow, deny, priority})
+                    }
+                    66 => commands::Command::RoleDelete(io::ask("Give role id.").parse().expect("Should be an integer")),
+                    _ => {
+                        let user: u64 = io::ask("Give user id.").parse().expect("Should be an integer");
+                        let role: u64 = io::ask("Give role id.").parse().expect("Should be an integer");
+                        if user_option == 67 {commands::Command::RoleGrant(user, role)}
+                        else {commands::Command::RoleRevoke(user, role)}
+                    }
                 };
-                // NEVER USE
-                // cur_place.borrow_mut().grant_role(user_to_mute_id, cur_place.borrow().find_role_by_id(2));
-                // You will fucking DIE!!!!!!!
-                // really... trust me bro
-                cur_place.borrow_mut().grant_role(user_to_mute_id, r);
+                match commands::execute(&mut sim, command) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            72 => {
+                if !perms.has(Permissions::CREATE_INVITES) {continue 'app;}
+                let uses = io::ask("Give max uses (empty for no limit).");
+                let minutes = io::ask("Give minutes until it expires (empty for never).");
+                let uses = if uses.is_empty() {None} else {Some(uses.parse().expect("Should be an integer"))};
+                let minutes: Option<u64> = if minutes.is_empty() {None} else {Some(minutes.parse().expect("Should be an integer"))};
+                match commands::execute(&mut sim, commands::Command::InviteCreate(uses, minutes)) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            73 => {
+                match commands::execute(&mut sim, commands::Command::Requests) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => {
+                        println!("{err}");
+                        continue 'app;
+                    }
+                }
+                let id = io::ask("Give request id (empty to skip).");
+                if id.is_empty() {continue 'app;}
+                let id: u64 = id.parse().expect("Should be an integer");
+                let approve = io::ask("Approve? (y/n)") == "y";
+                let response = io::ask("Message for the user (optional).");
+                let response = if response.is_empty() {None} else {Some(response)};
+                let command = if approve {commands::Command::RequestApprove(id, response)}
+                    else {commands::Command::RequestReject(id, response)};
+                match commands::execute(&mut sim, command) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            74 => {
+                if !perms.has(Permissions::MANAGE_PLACE) {continue 'app;}
+                let field = match io::ask("Pick setting: 1. description 2. topic 3. rules 4. welcome message 5. default role id 6. max message length").as_str() {
+                    "1" => commands::PlaceSetting::Description,
+                    "2" => commands::PlaceSetting::Topic,
+                    "3" => commands::PlaceSetting::Rules,
+                    "4" => commands::PlaceSetting::Welcome,
+                    "5" => commands::PlaceSetting::DefaultRole,
+                    "6" => commands::PlaceSetting::MaxLength,
+                    _ => {continue 'app;}
+                };
+                match commands::execute(&mut sim, commands::Command::SetSetting(field, io::ask("Give new value."))) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            75 => {
+                if !perms.has(Permissions::MANAGE_PLACE) {continue 'app;}
+                match commands::execute(&mut sim, commands::Command::ChannelCreate(io::ask("Give channel name."))) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            76 => {
+                if !perms.has(Permissions::MANAGE_ROLES) {continue 'app;}
+                let channel: u64 = io::ask("Give channel id.").parse().expect("Should be an integer");
+                let id: u64 = io::ask("Give role or user id.").parse().expect("Should be an integer");
+                let target = match io::ask("Is it a 1. role or 2. user?").as_str() {
+                    "2" => OverrideTarget::User(id),
+                    _ => OverrideTarget::Role(id),
+                };
+                let list = io::ask("Give permissions, e.g. +read_history -send_messages (empty removes the override)");
+                let words: Vec<&str> = list.split_whitespace().collect();
+                let (allow, deny) = match commands::parse_perm_list(&words) {
+                    Ok(x) => x,
+                    Err(err) => {
+                        println!("{err}");
+                        continue 'app;
+                    }
+                };
+                match commands::execute(&mut sim, commands::Command::ChannelPerms(channel, target, allow, deny)) {
+                    Ok(s) => println!("{s}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+
+            // Owner stuff
+            70 => {
+                let place_id = cur_place.borrow().id();
+                let new_owner: u64 = io::ask("Give new owner id.").parse().expect("Should be an integer");
+                match sim.transfer_ownership(place_id, new_owner) {
+                    Ok(_) => println!("Ownership transferred."),
+                    Err(err) => println!("{err}"),
+                }
+            }
+            71 => {
+                let place_id = cur_place.borrow().id();
+                if io::ask("Type 'delete' to confirm.") != "delete" {continue 'app;}
+                match sim.delete_place(place_id) {
+                    Ok(_) => println!("Place deleted."),
+                    Err(err) => println!("{err}"),
+                }
             }
             _ => {
                 continue 'app;
             }
         };
     }
-}
\ No newline at end of file
+}
+
+// Sample users and a place for trying things out, enabled with --demo
+fn seed_demo(sim: &mut Simulation) {
+    let madman = sim.create_user("Madman".to_string(), "Jonni".to_string());
+    sim.create_user("Femboy".to_string(), "Piofli".to_string());
+    sim.create_user("The Forgotten One".to_string(), "Diat".to_string());
+    sim.create_place("Debug".to_string(), madman);
+}
+
+fn reset_password(sim: &mut Simulation) {
+    if let Err(err) = sim.request_password_reset(&io::ask("E-mail:")) {
+        println!("{err}");
+        return;
+    }
+    println!("If this address belongs to a verified account, a reset token is on its way.");
+    let token = io::ask("Token:");
+    let new = io::get_new_password_from_console();
+    match sim.reset_password(&token, &new) {
+        Ok(()) => println!("Password changed, you can log in now"),
+        Err(err) => println!("{err}"),
+    }
+}
@@ -1,40 +1,103 @@
-use std::{cell::RefCell, fmt::Display, rc::{Rc, Weak}, time::SystemTime};
+use std::{cell::RefCell, fmt::Display, rc::{Rc, Weak}, time::{Duration, SystemTime}};
 use chrono::{DateTime, Local};
 use roles::RoleTrait;
 use super::user::User;
 
 pub mod roles {
-    use std::u8;
+    use std::fmt::Display;
+    use bitflags::bitflags;
 
     pub trait RoleTrait {
         fn return_perms(&self) -> RolePerms;
         fn update_perms(&mut self, source: RolePerms) -> RolePerms;
     }
 
+    bitflags! {
+        // Single permissions a role can hold
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct Permissions: u32 {
+            const SEND_MESSAGES = 1;
+            const READ_HISTORY = 1 << 1;
+            const MANAGE_MESSAGES = 1 << 2;
+            const BAN = 1 << 3;
+            const KICK = 1 << 4;
+            const MUTE = 1 << 5;
+            const MANAGE_ROLES = 1 << 6;
+            const MANAGE_PLACE = 1 << 7;
+            const CREATE_INVITES = 1 << 8;
+            const PIN = 1 << 9;
+            const MENTION_EVERYONE = 1 << 10;
+            const REACT = 1 << 11;
+        }
+    }
+
+    impl Permissions {
+        // What every new member gets
+        pub fn basic() -> Permissions {
+            Permissions::SEND_MESSAGES | Permissions::READ_HISTORY | Permissions::REACT
+        }
+    }
+
+    // Ids of the roles every place starts with
+    pub const BASIC_ROLE_ID: u64 = 0;
+    pub const ADMIN_ROLE_ID: u64 = 1;
+
     #[derive(Debug, Clone)]
     pub struct RoleTemplate {
         pub name: String,
+        pub(super) id: u64,
         perms: RolePerms
     }
 
     impl RoleTemplate {
+        // Id is given by the place when the role is added
         pub fn new(name: String, priority: u8) -> RoleTemplate {
             RoleTemplate {
                 name,
-                perms: RolePerms::new(true, priority)
+                id: 0,
+                perms: RolePerms::new(Permissions::basic(), Permissions::empty(), priority)
             }
         }
 
         pub fn new_basic() -> RoleTemplate {
-            Self::new("Basic".to_string(), 0)
+            RoleTemplate {
+                name: String::from("Basic"),
+                id: BASIC_ROLE_ID,
+                perms: RolePerms::new_basic()
+            }
         }
 
         pub fn new_admin() -> RoleTemplate {
             RoleTemplate {
                 name: String::from("Admin"),
+                id: ADMIN_ROLE_ID,
                 perms: RolePerms::new_admin()
             }
         }
+
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+
+        pub fn is_builtin(&self) -> bool {
+            self.id == BASIC_ROLE_ID || self.id == ADMIN_ROLE_ID
+        }
+    }
+
+    impl Display for RoleTemplate {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} (priority {}, allow: {}, deny: {})",
+                self.name, self.perms.priority, perm_names(self.perms.allow), perm_names(self.perms.deny))
+        }
+    }
+
+    // "send_messages, ban" style list for printing
+    pub fn perm_names(perms: Permissions) -> String {
+        if perms.is_empty() {return String::from("-");}
+        perms.iter_names()
+            .map(|(name, _)| name.to_lowercase())
+            .collect::<Vec<String>>()
+            .join(", ")
     }
 
     impl RoleTrait for RoleTemplate {
@@ -48,26 +111,124 @@ pub mod roles {
         }
     }
 
+    // Allowed and denied permissions of a single role.
+    // Permissions in neither set are left for other roles to decide.
     #[derive(Debug, Clone)]
     pub struct RolePerms {
-        pub can_talk: bool,
+        pub allow: Permissions,
+        pub deny: Permissions,
         pub priority: u8
     }
 
     impl RolePerms {
-        pub fn new(message: bool, priority: u8) -> RolePerms {
+        pub fn new(allow: Permissions, deny: Permissions, priority: u8) -> RolePerms {
             RolePerms {
-                can_talk: message,
+                allow,
+                deny,
                 priority
             }
         }
 
+        pub fn new_basic() -> RolePerms {
+            RolePerms {
+                allow: Permissions::basic(),
+                deny: Permissions::empty(),
+                priority: 0
+            }
+        }
+
         pub fn new_admin() -> RolePerms {
             RolePerms {
-                can_talk: true,
+                allow: Permissions::all(),
+                deny: Permissions::empty(),
                 priority: u8::MAX
             }
         }
+
+        pub fn has(&self, perm: Permissions) -> bool {
+            self.allow.contains(perm) && !self.deny.intersects(perm)
+        }
+    }
+
+    // Which role decided a single permission
+    #[derive(Debug, Clone)]
+    pub struct PermSource {
+        pub name: &'static str,
+        pub perm: Permissions,
+        pub granted: bool,
+        pub role: Option<String>
+    }
+
+    impl Display for PermSource {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let state = if self.granted {"granted"} else {"denied"};
+            match &self.role {
+                Some(role) => write!(f, "{}: {} by {}", self.name, state, role),
+                None => write!(f, "{}: {} (no role sets it)", self.name, state),
+            }
+        }
+    }
+
+    // One set of perms taking part in resolution: a place role or a channel override
+    #[derive(Debug, Clone)]
+    pub struct PermLayer {
+        pub label: String,
+        pub perms: RolePerms,
+        // Place roles are 0, more specific layers are higher
+        pub rank: u8
+    }
+
+    impl PermLayer {
+        pub fn from_role(role: &RoleTemplate) -> PermLayer {
+            PermLayer {
+                label: role.name.clone(),
+                perms: role.return_perms(),
+                rank: 0
+            }
+        }
+    }
+
+    // Resolve every permission over a set of roles.
+    pub fn resolve(roles: &[&RoleTemplate]) -> Vec<PermSource> {
+        let layers: Vec<PermLayer> = roles.iter().map(|r| PermLayer::from_role(r)).collect();
+        resolve_layers(&layers)
+    }
+
+    // The highest priority layer that allows or denies a permission decides it.
+    // On equal priority the higher rank wins, then deny wins.
+    // Permissions no layer mentions are denied.
+    pub fn resolve_layers(layers: &[PermLayer]) -> Vec<PermSource> {
+        let mut out = vec![];
+        for (name, perm) in Permissions::all().iter_names() {
+            // ((priority, rank, denied), label)
+            let mut best: Option<((u8, u8, bool), &str)> = None;
+            for layer in layers.iter() {
+                let denied = layer.perms.deny.contains(perm);
+                if !denied && !layer.perms.allow.contains(perm) {continue;}
+                let key = (layer.perms.priority, layer.rank, denied);
+                let wins = match best {
+                    None => true,
+                    Some((best_key, _)) => key > best_key,
+                };
+                if wins {best = Some((key, layer.label.as_str()));}
+            }
+            out.push(PermSource {
+                name,
+                perm,
+                granted: matches!(best, Some(((_, _, false), _))),
+                role: best.map(|(_, label)| label.to_string()),
+            });
+        }
+        out
+    }
+
+    // Fold a resolved list back into a single set of perms
+    pub fn combine(sources: &[PermSource], priority: u8) -> RolePerms {
+        let mut allow = Permissions::empty();
+        for s in sources.iter().filter(|s| s.granted) {
+            allow |= s.perm;
+        }
+        RolePerms::new(allow, Permissions::empty(), priority)
     }
 
     #[cfg(test)]
@@ -77,60 +238,439 @@ pub mod roles {
         #[test]
         fn check_clone(){
             let mut role1 = RoleTemplate::new("test".to_string(), 0);
-            role1.perms.can_talk = false;
+            role1.perms.allow.remove(Permissions::SEND_MESSAGES);
             let perms = role1.return_perms();
-            assert_eq!(role1.perms.can_talk, perms.can_talk);
+            assert_eq!(role1.perms.allow, perms.allow);
+        }
+
+        #[test]
+        fn check_presets(){
+            let basic = RoleTemplate::new_basic().return_perms();
+            assert!(basic.has(Permissions::SEND_MESSAGES));
+            assert!(!basic.has(Permissions::BAN));
+            let admin = RoleTemplate::new_admin().return_perms();
+            assert!(admin.has(Permissions::BAN | Permissions::MANAGE_ROLES));
+        }
+
+        #[test]
+        fn check_deny_overrides(){
+            let basic = RoleTemplate::new_basic();
+            let mut muted = RoleTemplate::new("Muted".to_string(), 0);
+            muted.update_perms(RolePerms::new(Permissions::empty(), Permissions::SEND_MESSAGES, 0));
+            let perms = combine(&resolve(&[&basic, &muted]), 0);
+            assert!(!perms.has(Permissions::SEND_MESSAGES));
+            assert!(perms.has(Permissions::READ_HISTORY));
+
+            // Higher priority allow beats lower deny
+            let admin = RoleTemplate::new_admin();
+            let perms = combine(&resolve(&[&basic, &muted, &admin]), 0);
+            assert!(perms.has(Permissions::SEND_MESSAGES));
+        }
+    }
+}
+
+// Who can join and who can see the place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Public,
+    InviteOnly,
+    // Joining creates a request admins have to approve
+    RequestToJoin,
+    // Invite only and left out of place lists
+    Hidden
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestStatus {
+    Pending,
+    Approved,
+    Rejected
+}
+
+#[derive(Debug, Clone)]
+pub struct JoinRequest {
+    pub id: u64,
+    pub user_id: u64,
+    pub status: RequestStatus,
+    // Optional note from the admin who handled it
+    pub response: Option<String>,
+    pub time: SystemTime
+}
+
+impl Display for JoinRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self.status {
+            RequestStatus::Pending => "pending",
+            RequestStatus::Approved => "approved",
+            RequestStatus::Rejected => "rejected",
+        };
+        write!(f, "Request {} from user {} at {}: {}", self.id, self.user_id, DateTime::<Local>::from(self.time), status)?;
+        if let Some(response) = &self.response {write!(f, " ({response})")?;}
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub code: String,
+    pub created_by: u64,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub expires: Option<SystemTime>
+}
+
+impl Invite {
+    pub fn new(code: String, created_by: u64, max_uses: Option<u32>, valid_for: Option<Duration>) -> Invite {
+        Invite {
+            code,
+            created_by,
+            max_uses,
+            uses: 0,
+            expires: valid_for.map(|d| SystemTime::now() + d)
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        if let Some(max) = self.max_uses {
+            if self.uses >= max {return false;}
+        }
+        match self.expires {
+            Some(time) => SystemTime::now() < time,
+            None => true,
+        }
+    }
+}
+
+impl Display for Invite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} used {}", self.code, self.uses)?;
+        if let Some(max) = self.max_uses {write!(f, "/{max}")?;}
+        if let Some(time) = self.expires {write!(f, ", expires {}", DateTime::<Local>::from(time))?;}
+        Ok(())
+    }
+}
+
+// One row of the place directory
+#[derive(Debug, Clone)]
+pub struct PlaceListing {
+    pub id: u64,
+    pub name: String,
+    pub description: String,
+    pub visibility: Visibility,
+    pub member_count: usize,
+    pub last_activity: Option<SystemTime>,
+    pub is_member: bool,
+    pub is_banned: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaceSort {
+    Id,
+    Name,
+    // Most members first
+    Members,
+    // Most recently active first
+    Activity
+}
+
+impl Display for PlaceListing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. {}", self.id, self.name)?;
+        if self.is_banned {write!(f, " [banned]")?;}
+        else if self.is_member {write!(f, " [member]")?;}
+        match self.visibility {
+            Visibility::InviteOnly => write!(f, " (invite only)")?,
+            Visibility::RequestToJoin => write!(f, " (request to join)")?,
+            Visibility::Hidden => write!(f, " (hidden)")?,
+            Visibility::Public => {},
+        }
+        write!(f, " - {} members", self.member_count)?;
+        if let Some(time) = self.last_activity {
+            write!(f, ", last active {}", DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M"))?;
+        }
+        if !self.description.is_empty() {write!(f, "\n   {}", self.description)?;}
+        Ok(())
+    }
+}
+
+// Id of the channel every place starts with
+pub const GENERAL_CHANNEL_ID: u64 = 0;
+
+// Who a channel override applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverrideTarget {
+    Role(u64),
+    User(u64)
+}
+
+// Allow and deny for one role or user, applied on top of the place roles
+#[derive(Debug, Clone)]
+pub struct ChannelOverride {
+    pub target: OverrideTarget,
+    pub allow: roles::Permissions,
+    pub deny: roles::Permissions
+}
+
+// Named message stream inside a place
+#[derive(Debug)]
+pub struct Channel {
+    pub name: String,
+    id: u64,
+    pub topic: String,
+    pub messages: Vec<PlaceMessage>,
+    overrides: Vec<ChannelOverride>
+}
+
+impl Channel {
+    pub fn new(name: String, id: u64) -> Channel {
+        Channel {
+            name,
+            id,
+            topic: String::new(),
+            messages: vec![],
+            overrides: vec![]
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn return_overrides(&self) -> &Vec<ChannelOverride> {
+        &self.overrides
+    }
+
+    // Empty allow and deny removes the override
+    pub fn set_override(&mut self, target: OverrideTarget, allow: roles::Permissions, deny: roles::Permissions) {
+        self.overrides.retain(|o| o.target != target);
+        if allow.is_empty() && deny.is_empty() {return;}
+        self.overrides.push(ChannelOverride {target, allow, deny});
+    }
+
+    pub fn last_activity(&self) -> Option<SystemTime> {
+        self.messages.iter().map(|m| m.time()).max()
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. #{}", self.id, self.name)?;
+        if !self.topic.is_empty() {write!(f, " - {}", self.topic)?;}
+        Ok(())
+    }
+}
+
+// Place config editable by admins
+#[derive(Debug, Clone)]
+pub struct PlaceSettings {
+    pub description: String,
+    pub topic: String,
+    pub rules: String,
+    // Shown to users joining for the first time
+    pub welcome_message: String,
+    // Given to newcomers on top of Basic
+    pub default_role: u64,
+    pub max_message_length: usize
+}
+
+impl PlaceSettings {
+    pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 2000;
+
+    pub fn new() -> PlaceSettings {
+        PlaceSettings {
+            description: String::new(),
+            topic: String::new(),
+            rules: String::new(),
+            welcome_message: String::new(),
+            default_role: roles::BASIC_ROLE_ID,
+            max_message_length: Self::DEFAULT_MAX_MESSAGE_LENGTH
         }
     }
 }
 
+impl Default for PlaceSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for PlaceSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Description: {}\nTopic: {}\nRules: {}\nWelcome message: {}\nDefault role: {}\nMax message length: {}",
+            self.description, self.topic, self.rules, self.welcome_message, self.default_role, self.max_message_length)
+    }
+}
+
 #[derive(Debug)]
 pub struct Place {
     pub name: String,
+    settings: PlaceSettings,
     id: u64,
     pub members: Vec<PlaceUser>,
-    admin: Vec<Weak<RefCell<User>>>,
-    pub messages: Vec<PlaceMessage>,
-    roles: Vec<roles::RoleTemplate>
+    // Id of the user owning the place
+    owner: Option<u64>,
+    channels: Vec<Channel>,
+    next_channel_id: u64,
+    roles: Vec<roles::RoleTemplate>,
+    next_role_id: u64,
+    visibility: Visibility,
+    invites: Vec<Invite>,
+    join_requests: Vec<JoinRequest>,
+    next_request_id: u64
 }
 
 impl Place {
-    pub fn new(name: String, admin: Option<Rc<RefCell<User>>>, id: u64) -> Place { 
+    pub fn new(name: String, owner: Option<Rc<RefCell<User>>>, id: u64) -> Place { 
         // Default roles
         // Basic
         // Admin
 
         let mut p = Place{
             name,
+            settings: PlaceSettings::new(),
             members: vec![],
-            admin: vec![],
+            owner: None,
             id,
-            messages: vec![],
+            channels: vec![Channel::new(String::from("general"), GENERAL_CHANNEL_ID)],
+            next_channel_id: GENERAL_CHANNEL_ID + 1,
             roles: vec![
                 roles::RoleTemplate::new_basic(),
                 roles::RoleTemplate::new_admin()
-            ]
+            ],
+            next_role_id: roles::ADMIN_ROLE_ID + 1,
+            visibility: Visibility::Public,
+            invites: vec![],
+            join_requests: vec![],
+            next_request_id: 0
         };
-        if let Some(user) = admin {
-            p.admin.push(Rc::downgrade(&user));
-            p.add_admin(user);
+        if let Some(user) = owner {
+            let owner_id = user.borrow().id();
+            p.owner = Some(owner_id);
+            p.add_user(user);
+            p.add_admin(owner_id);
         };
         p
     }
 
     pub fn add_user(&mut self, user: Rc<RefCell<User>>){
-        let x = PlaceUser::new(Rc::downgrade(&user));
+        let mut x = PlaceUser::new(Rc::downgrade(&user));
+        let default_role = self.settings.default_role;
+        if default_role != roles::BASIC_ROLE_ID && self.find_role_by_id(default_role).is_some() {
+            x.add_role(default_role);
+        }
         self.members.push(x);
     }
 
-    pub fn add_admin(&mut self, user: Rc<RefCell<User>>) {
-        let mut x = PlaceUser::new(Rc::downgrade(&user));
-        x.add_admin_role();
-        self.members.push(x);
+    pub fn settings(&self) -> &PlaceSettings {
+        &self.settings
+    }
+
+    // Permission checks are up to the caller
+    pub fn update_settings(&mut self, settings: PlaceSettings) -> Result<(), &'static str> {
+        if self.find_role_by_id(settings.default_role).is_none() {return Err("Can't find the default role");}
+        if settings.max_message_length == 0 {return Err("Max message length has to be at least 1");}
+        self.settings = settings;
+        Ok(())
+    }
+
+    // True only on the member's first visit since joining
+    pub fn take_welcome(&mut self, user_id: u64) -> bool {
+        match self.find_user_by_id_mut(user_id) {
+            Some(user) if !user.welcomed => {
+                user.welcomed = true;
+                true
+            }
+            _ => false
+        }
+    }
+
+    // Gives an existing member the Admin role if they don't hold it yet
+    fn add_admin(&mut self, user_id: u64) {
+        if let Some(user) = self.find_user_by_id_mut(user_id) {
+            if !user.return_roles().contains(&roles::ADMIN_ROLE_ID) {
+                user.add_admin_role();
+            }
+        }
+    }
+
+    pub fn add_message(&mut self, channel_id: u64, message: PlaceMessage) -> Result<(), &'static str> {
+        let channel = self.find_channel_by_id_mut(channel_id).ok_or("Can't find the channel")?;
+        channel.messages.push(message);
+        Ok(())
+    }
+
+    // Time of the newest message in any channel
+    pub fn last_activity(&self) -> Option<SystemTime> {
+        self.channels.iter().filter_map(|c| c.last_activity()).max()
+    }
+
+    // Channels
+    pub fn return_channels(&self) -> &Vec<Channel> {
+        &self.channels
+    }
+
+    pub fn find_channel_by_id(&self, channel_id: u64) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.id() == channel_id)
+    }
+
+    pub fn find_channel_by_id_mut(&mut self, channel_id: u64) -> Option<&mut Channel> {
+        self.channels.iter_mut().find(|c| c.id() == channel_id)
+    }
+
+    pub fn find_channel_by_name(&self, name: &str) -> Option<&Channel> {
+        let name = name.trim_start_matches('#').to_lowercase();
+        self.channels.iter().find(|c| c.name.to_lowercase() == name)
+    }
+
+    pub fn add_channel(&mut self, name: String) -> Result<u64, &'static str> {
+        let name = name.trim_start_matches('#').trim().to_string();
+        if name.is_empty() {return Err("Channel name can't be empty");}
+        if self.find_channel_by_name(&name).is_some() {return Err("Channel with this name already exists");}
+        let id = self.next_channel_id;
+        self.next_channel_id += 1;
+        self.channels.push(Channel::new(name, id));
+        Ok(id)
+    }
+
+    pub fn remove_channel(&mut self, channel_id: u64) -> Result<(), &'static str> {
+        if channel_id == GENERAL_CHANNEL_ID {return Err("The general channel can't be deleted");}
+        if self.find_channel_by_id(channel_id).is_none() {return Err("Can't find the channel");}
+        self.channels.retain(|c| c.id() != channel_id);
+        Ok(())
+    }
+
+    pub fn set_channel_override(&mut self, channel_id: u64, target: OverrideTarget, allow: roles::Permissions, deny: roles::Permissions) -> Result<(), &'static str> {
+        match target {
+            OverrideTarget::Role(id) => if self.find_role_by_id(id).is_none() {return Err("Can't find the role");},
+            OverrideTarget::User(id) => if self.find_user_by_id(id).is_none() {return Err("Error, can't find the user");},
+        }
+        let channel = self.find_channel_by_id_mut(channel_id).ok_or("Can't find the channel")?;
+        channel.set_override(target, allow, deny);
+        Ok(())
     }
 
-    pub fn add_message(&mut self, message: PlaceMessage) {
-        self.messages.push(message);
+    // Same as has_perm, scoped to a channel
+    pub fn has_channel_perm(&self, user_id: u64, channel_id: u64, perm: roles::Permissions) -> bool {
+        if self.find_user_by_id(user_id).is_none_or(|u| u.banned) {return false;}
+        self.return_perms(user_id, Some(channel_id)).has(perm)
+    }
+
+    // Directory entry of the place as seen by the user
+    pub fn listing_for(&self, user_id: u64) -> PlaceListing {
+        let is_banned = self.is_banned(user_id);
+        let is_member = self.find_user_by_id(user_id).is_some() && !is_banned;
+        // Private places only show details to members
+        let details = is_member || self.visibility != Visibility::InviteOnly;
+        PlaceListing {
+            id: self.id,
+            name: self.name.clone(),
+            description: if details {self.settings.description.clone()} else {String::new()},
+            visibility: self.visibility,
+            member_count: self.members.iter().filter(|m| !m.banned).count(),
+            last_activity: if details {self.last_activity()} else {None},
+            is_member,
+            is_banned
+        }
     }
     
     pub fn id(&self) -> u64 {
@@ -138,84 +678,329 @@ impl Place {
     }
 
     pub fn find_user_by_id(&self, id: u64) -> Option<&PlaceUser> {
-        for user in self.members.iter(){
-            if user.user.upgrade().unwrap().borrow().id() == id {return Some(user);}
-        }
-        return None;
+        self.members.iter().find(|user| user.user.upgrade().unwrap().borrow().id() == id)
     }
 
     fn find_user_by_id_mut(&mut self, id: u64) -> Option<&mut PlaceUser> {
-        for user in self.members.iter_mut(){
-            if user.user.upgrade().unwrap().borrow().id() == id {return Some(user);}
-        }
-        return None;
+        self.members.iter_mut().find(|user| user.user.upgrade().unwrap().borrow().id() == id)
+    }
+
+    pub fn owner(&self) -> Option<u64> {
+        self.owner
+    }
+
+    pub fn is_owner(&self, user_id: u64) -> bool {
+        self.owner == Some(user_id)
+    }
+
+    // New owner has to be a member that isn't banned.
+    // They get the Admin role if they didn't have it yet.
+    pub fn transfer_ownership(&mut self, new_owner: u64) -> Result<(), &'static str> {
+        let user = self.find_user_by_id_mut(new_owner).ok_or("New owner has to be a member of the place")?;
+        if user.banned {return Err("Can't give the place to a banned user");}
+        self.add_admin(new_owner);
+        self.owner = Some(new_owner);
+        Ok(())
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn set_visibility(&mut self, visibility: Visibility) {
+        self.visibility = visibility;
+    }
+
+    // Hidden places don't show up in any listing
+    pub fn is_listed(&self) -> bool {
+        self.visibility != Visibility::Hidden
+    }
+
+    // Invites
+    pub fn add_invite(&mut self, invite: Invite) {
+        self.invites.push(invite);
+    }
+
+    pub fn return_invites(&self) -> &Vec<Invite> {
+        &self.invites
+    }
+
+    pub fn find_invite(&self, code: &str) -> Option<&Invite> {
+        self.invites.iter().find(|i| i.code == code)
     }
 
-    pub fn find_admin(&self, id: u64) -> Option<Rc<RefCell<User>>> {
-        for user in self.admin.iter() {
-            if user.upgrade().unwrap().borrow().id() == id {return Some(user.upgrade().unwrap());}
+    pub fn revoke_invite(&mut self, code: &str) -> Result<(), &'static str> {
+        if self.find_invite(code).is_none() {return Err("Can't find the invite");}
+        self.invites.retain(|i| i.code != code);
+        Ok(())
+    }
+
+    // Counts one use, used up and expired invites are dropped
+    pub fn use_invite(&mut self, code: &str) -> Result<(), &'static str> {
+        let invite = self.invites.iter_mut().find(|i| i.code == code).ok_or("Invalid invite code")?;
+        if !invite.is_valid() {
+            self.invites.retain(|i| i.code != code);
+            return Err("This invite has expired");
         }
-        return None
+        invite.uses += 1;
+        self.invites.retain(|i| i.is_valid());
+        Ok(())
+    }
+
+    // Join requests
+    pub fn return_join_requests(&self) -> &Vec<JoinRequest> {
+        &self.join_requests
+    }
+
+    pub fn pending_request_of(&self, user_id: u64) -> Option<&JoinRequest> {
+        self.join_requests.iter().find(|r| r.user_id == user_id && r.status == RequestStatus::Pending)
+    }
+
+    pub fn add_join_request(&mut self, user_id: u64) -> Result<u64, &'static str> {
+        if self.find_user_by_id(user_id).is_some() {return Err("You are already a member");}
+        if self.pending_request_of(user_id).is_some() {return Err("You already asked to join this place");}
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        self.join_requests.push(JoinRequest {
+            id,
+            user_id,
+            status: RequestStatus::Pending,
+            response: None,
+            time: SystemTime::now()
+        });
+        Ok(id)
+    }
+
+    // Marks a pending request as handled and returns the requesting user id.
+    // Adding the member is up to the caller.
+    pub fn resolve_join_request(&mut self, request_id: u64, status: RequestStatus, response: Option<String>) -> Result<u64, &'static str> {
+        let request = self.join_requests.iter_mut().find(|r| r.id == request_id).ok_or("Can't find the request")?;
+        if request.status != RequestStatus::Pending {return Err("This request was already handled");}
+        request.status = status;
+        request.response = response;
+        Ok(request.user_id)
+    }
+
+    // Admins are whoever can manage the place, through any role
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        self.has_perm(user_id, roles::Permissions::MANAGE_PLACE)
     }
 
     // Roles and perms and shit
-    pub fn return_perms(&self, user_id: u64) -> roles::RolePerms {
-        let user = self.find_user_by_id(user_id).unwrap();
-        let mut target: &roles::RoleTemplate = &roles::RoleTemplate::new("0".to_string(), 0);
+    // With a channel its overrides are resolved together with the place roles
+    pub fn return_perms(&self, user_id: u64, channel_id: Option<u64>) -> roles::RolePerms {
+        let priority = self.user_role_templates(user_id).iter()
+            .map(|r| r.return_perms().priority)
+            .max().unwrap_or(0);
+        roles::combine(&self.explain_perms(user_id, channel_id), priority)
+    }
+
+    // Every permission of the user together with the role or override that decided it.
+    // Channel role overrides beat the place role at the same priority,
+    // user overrides beat every role. The owner gets theirs from the Admin role they can't lose.
+    pub fn explain_perms(&self, user_id: u64, channel_id: Option<u64>) -> Vec<roles::PermSource> {
+        let roles = self.user_role_templates(user_id);
         // Check len
-        if user.return_roles().len() == 0 {panic!("There should be default role");}
-        for t in user.return_roles().iter() {
-            if target.return_perms().priority < t.return_perms().priority {
-                target = t;
+        if roles.is_empty() {panic!("There should be default role");}
+        let mut layers: Vec<roles::PermLayer> = roles.iter().map(|r| roles::PermLayer::from_role(r)).collect();
+        if let Some(channel) = channel_id.and_then(|id| self.find_channel_by_id(id)) {
+            for o in channel.return_overrides().iter() {
+                let layer = match o.target {
+                    OverrideTarget::Role(id) => match roles.iter().find(|r| r.id() == id) {
+                        Some(role) => roles::PermLayer {
+                            label: format!("{} in #{}", role.name, channel.name),
+                            perms: roles::RolePerms::new(o.allow, o.deny, role.return_perms().priority),
+                            rank: 1
+                        },
+                        None => {continue;}
+                    },
+                    OverrideTarget::User(id) if id == user_id => roles::PermLayer {
+                        label: format!("User override in #{}", channel.name),
+                        perms: roles::RolePerms::new(o.allow, o.deny, u8::MAX),
+                        rank: 2
+                    },
+                    OverrideTarget::User(_) => {continue;}
+                };
+                layers.push(layer);
             }
         }
-        target.return_perms()
+        roles::resolve_layers(&layers)
     }
 
-    pub fn update_roles(&mut self, user_id: u64, new_role: roles::RoleTemplate) -> Result<roles::RolePerms, &'static str> {
-        let user = match self.find_user_by_id_mut(user_id) {
-            Some(x) => x,
-            None => {return Err("Error, can't find the user")}
-        };
-        for p in user.return_roles_mut().iter_mut() {
-            if p.return_perms().priority == new_role.return_perms().priority {
-                p.update_perms(new_role.return_perms());
-                return Ok(new_role.return_perms().clone());
-            }
+    fn user_role_templates(&self, user_id: u64) -> Vec<&roles::RoleTemplate> {
+        let user = self.find_user_by_id(user_id).unwrap();
+        user.return_roles().iter()
+            .filter_map(|id| self.find_role_by_id(*id))
+            .collect()
+    }
+
+    // Whether the actor sits above the priority. The owner is above every role.
+    fn outranks(&self, actor_id: u64, priority: u8) -> bool {
+        self.is_owner(actor_id) || priority < self.return_perms(actor_id, None).priority
+    }
+
+    // Members only manage roles below their own top role
+    // and can't hand out permissions they don't hold themselves
+    pub fn check_role_rank(&self, actor_id: u64, perms: &roles::RolePerms) -> Result<(), &'static str> {
+        if !self.outranks(actor_id, perms.priority) {
+            return Err("You can only manage roles below your own");
+        }
+        if !self.return_perms(actor_id, None).allow.contains(perms.allow) {
+            return Err("You can't grant permissions you don't have");
+        }
+        Ok(())
+    }
+
+    // Members only act on members below their own top role, never on the owner or themselves
+    pub fn check_member_rank(&self, actor_id: u64, target_id: u64) -> Result<(), &'static str> {
+        if self.find_user_by_id(target_id).is_none() {return Err("Error, can't find the user");}
+        let priority = self.return_perms(target_id, None).priority;
+        if target_id == actor_id || self.is_owner(target_id) || !self.outranks(actor_id, priority) {
+            return Err("You can only manage members below you");
         }
-        user.add_role(new_role);
-        Ok(self.return_perms(user_id)) // We know user is here so unwrap bois
+        Ok(())
+    }
+
+    // Same check for an existing role. Handing it out also needs every permission it allows.
+    pub fn check_role_id_rank(&self, actor_id: u64, role_id: u64, handing_out: bool) -> Result<(), &'static str> {
+        let mut perms = self.find_role_by_id(role_id).ok_or("Can't find the role")?.return_perms();
+        if !handing_out {perms.allow = roles::Permissions::empty();}
+        self.check_role_rank(actor_id, &perms)
+    }
+
+    // Same rules for channel overrides. User overrides beat every role,
+    // so they only go to members below the actor.
+    pub fn check_override_rank(&self, actor_id: u64, target: OverrideTarget, allow: roles::Permissions) -> Result<(), &'static str> {
+        let priority = match target {
+            OverrideTarget::Role(id) => self.find_role_by_id(id).ok_or("Can't find the role")?.return_perms().priority,
+            OverrideTarget::User(id) => {
+                self.check_member_rank(actor_id, id)?;
+                self.return_perms(id, None).priority
+            }
+        };
+        self.check_role_rank(actor_id, &roles::RolePerms::new(allow, roles::Permissions::empty(), priority))
+    }
+
+    // Same as return_perms, but safe for users outside the place.
+    // Banned members stay on record without any permissions.
+    pub fn has_perm(&self, user_id: u64, perm: roles::Permissions) -> bool {
+        if self.find_user_by_id(user_id).is_none_or(|u| u.banned) {return false;}
+        self.return_perms(user_id, None).has(perm)
     }
 
     pub fn return_role_vec(&self) -> &Vec<roles::RoleTemplate> {
         &self.roles
     }
 
-    pub fn add_role(&mut self, new_role: roles::RoleTemplate) {
+    // Gives the role a fresh id and returns it.
+    // Role changes don't check ranks, outside the crate they go through Simulation.
+    pub(crate) fn add_role(&mut self, mut new_role: roles::RoleTemplate) -> Result<u64, &'static str> {
+        if new_role.name.trim().is_empty() {return Err("Role name can't be empty");}
+        if self.find_role_by_name(new_role.name.clone()).is_some() {
+            return Err("Role with this name already exists");
+        }
+        new_role.id = self.next_role_id;
+        self.next_role_id += 1;
         self.roles.push(new_role);
+        Ok(self.next_role_id - 1)
+    }
+
+    pub(crate) fn create_role(&mut self, name: String, perms: roles::RolePerms) -> Result<u64, &'static str> {
+        let mut role = roles::RoleTemplate::new(name, perms.priority);
+        role.update_perms(perms);
+        self.add_role(role)
+    }
+
+    pub fn rename_role(&mut self, role_id: u64, name: String) -> Result<(), &'static str> {
+        if name.trim().is_empty() {return Err("Role name can't be empty");}
+        if let Some(other) = self.find_role_by_name(name.clone()) {
+            if other.id() != role_id {return Err("Role with this name already exists");}
+        }
+        let role = self.find_role_by_id_mut(role_id).ok_or("Can't find the role")?;
+        role.name = name;
+        Ok(())
+    }
+
+    // Changes take effect for every member holding the role
+    pub(crate) fn edit_role_perms(&mut self, role_id: u64, perms: roles::RolePerms) -> Result<(), &'static str> {
+        let role = self.find_role_by_id_mut(role_id).ok_or("Can't find the role")?;
+        if role.is_builtin() {return Err("Built-in roles can't be edited");}
+        role.update_perms(perms);
+        Ok(())
     }
 
-    pub fn find_role_by_id(&self, role_id: usize) -> Option<&roles::RoleTemplate> {
-        self.roles.get(role_id)
+    pub fn delete_role(&mut self, role_id: u64) -> Result<(), &'static str> {
+        let role = self.find_role_by_id(role_id).ok_or("Can't find the role")?;
+        if role.is_builtin() {return Err("Built-in roles can't be deleted");}
+        self.roles.retain(|r| r.id() != role_id);
+        if self.settings.default_role == role_id {
+            self.settings.default_role = roles::BASIC_ROLE_ID;
+        }
+        for channel in self.channels.iter_mut() {
+            channel.overrides.retain(|o| o.target != OverrideTarget::Role(role_id));
+        }
+        for user in self.members.iter_mut() {
+            user.remove_role(role_id);
+        }
+        Ok(())
+    }
+
+    pub fn find_role_by_id(&self, role_id: u64) -> Option<&roles::RoleTemplate> {
+        self.roles.iter().find(|r| r.id() == role_id)
+    }
+
+    fn find_role_by_id_mut(&mut self, role_id: u64) -> Option<&mut roles::RoleTemplate> {
+        self.roles.iter_mut().find(|r| r.id() == role_id)
     }
 
     pub fn find_role_by_name(&self, role_name: String) -> Option<&roles::RoleTemplate> {
         let new_name = role_name.to_lowercase();
-        for r in self.roles.iter() {
-            let r_name = r.name.to_lowercase();
-            if new_name == r_name {
-                return Some(r)
-            }
+        self.roles.iter().find(|r| r.name.to_lowercase() == new_name)
+    }
+
+    pub(crate) fn grant_role(&mut self, user_id: u64, role_id: u64) -> Result<(), &'static str> {
+        if self.find_role_by_id(role_id).is_none() {return Err("Can't find the role");}
+        let user = self.find_user_by_id_mut(user_id).ok_or("Error, can't find the user")?;
+        if user.return_roles().contains(&role_id) {return Err("User already has this role");}
+        user.add_role(role_id);
+        Ok(())
+    }
+
+    pub fn revoke_role(&mut self, user_id: u64, role_id: u64) -> Result<(), &'static str> {
+        if role_id == roles::BASIC_ROLE_ID {return Err("Basic role can't be revoked");}
+        if role_id == roles::ADMIN_ROLE_ID && self.is_owner(user_id) {return Err("The owner keeps the Admin role");}
+        let user = self.find_user_by_id_mut(user_id).ok_or("Error, can't find the user")?;
+        if !user.remove_role(role_id) {return Err("User doesn't have this role");}
+        Ok(())
+    }
+
+    // Drops the member with their roles and channel overrides.
+    // Banned members stay on record so the ban still holds.
+    pub fn remove_user(&mut self, user_id: u64) -> Result<(), &'static str> {
+        let user = self.find_user_by_id(user_id).ok_or("Error, can't find the user")?;
+        if user.banned {return Ok(());}
+        self.members.retain(|m| m.user.upgrade().unwrap().borrow().id() != user_id);
+        for channel in self.channels.iter_mut() {
+            channel.overrides.retain(|o| o.target != OverrideTarget::User(user_id));
         }
-        None
+        Ok(())
     }
 
-    pub fn grant_role(&mut self, user_id: u64, new_role: roles::RoleTemplate) {
-        let user = match self.find_user_by_id_mut(user_id) {
-            Some(user) => user,
-            None => {return;}
-        };
-        user.add_role(new_role);
+    // Removes every trace of a deleted account, bans included.
+    // Must run while the user is still alive, members hold only Weak refs.
+    pub fn erase_user(&mut self, user_id: u64) {
+        self.members.retain(|m| m.user.upgrade().is_some_and(|u| u.borrow().id() != user_id));
+        for channel in self.channels.iter_mut() {
+            channel.overrides.retain(|o| o.target != OverrideTarget::User(user_id));
+        }
+        self.join_requests.retain(|r| r.user_id != user_id);
+        self.invites.retain(|i| i.created_by != user_id);
+    }
+
+    // Join and leave events go to the general channel
+    pub fn add_event(&mut self, user: &Rc<RefCell<User>>, kind: MessageKind) {
+        let event = PlaceMessage::new_event(user, kind, SystemTime::now());
+        self.add_message(GENERAL_CHANNEL_ID, event).expect("General channel always exists");
     }
 
     // Banicja methods
@@ -231,7 +1016,7 @@ impl Place {
         // ban moron
         let target_user = target_user.unwrap();
         target_user.banned = true;
-        return target_user.banned;
+        target_user.banned
     }
 
     pub fn is_banned(&self, user: u64) -> bool {
@@ -242,11 +1027,22 @@ impl Place {
     }
 }
 
+pub const DELETED_USER_NAME: &str = "Deleted user";
+
 #[derive(Debug)]
 pub struct PlaceMessage {
     user: Weak<RefCell<User>>,
     message: String,
-    time: SystemTime
+    time: SystemTime,
+    pub kind: MessageKind
+}
+
+// Text from a user or a membership event shown in the history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    Text,
+    Joined,
+    Left
 }
 
 impl PlaceMessage {
@@ -254,48 +1050,81 @@ impl PlaceMessage {
         PlaceMessage {
             user: Rc::downgrade(user),
             message,
-            time
+            time,
+            kind: MessageKind::Text
         }
     }
+
+    pub fn new_event(user: &Rc<RefCell<User>>, kind: MessageKind, time: SystemTime) -> PlaceMessage {
+        PlaceMessage {
+            user: Rc::downgrade(user),
+            message: String::new(),
+            time,
+            kind
+        }
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    pub fn user_id(&self) -> Option<u64> {
+        self.user.upgrade().map(|u| u.borrow().id())
+    }
 }
 
 impl Display for PlaceMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} at {}:\n{}", self.user.upgrade().unwrap().borrow().name(), DateTime::<Local>::from(self.time), self.message)
+        // Authors of deleted accounts are gone, their messages stay
+        let name = match self.user.upgrade() {
+            Some(user) => user.borrow().name(),
+            None => String::from(DELETED_USER_NAME),
+        };
+        let time = DateTime::<Local>::from(self.time);
+        match self.kind {
+            MessageKind::Text => write!(f, "{} at {}:\n{}", name, time, self.message),
+            MessageKind::Joined => write!(f, "* {} joined the place at {}", name, time),
+            MessageKind::Left => write!(f, "* {} left the place at {}", name, time),
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct PlaceUser {
     pub user: Weak<RefCell<User>>,
-    pub roles: Vec<roles::RoleTemplate>,
-    pub banned: bool
+    // Ids of roles from Place.roles
+    pub roles: Vec<u64>,
+    pub banned: bool,
+    // Welcome message waits for the first visit
+    pub welcomed: bool
 }  
 
 impl PlaceUser {
     pub fn new(user: Weak<RefCell<User>>) -> PlaceUser{
-        let role = roles::RoleTemplate::new_basic();
         PlaceUser {
             user,
-            roles: vec![role],
-            banned: false
+            roles: vec![roles::BASIC_ROLE_ID],
+            banned: false,
+            welcomed: false
         }
     }
 
     pub fn add_admin_role(&mut self) {
-        let admin = roles::RoleTemplate::new_admin();
-        self.roles.push(admin);
+        self.roles.push(roles::ADMIN_ROLE_ID);
     }
 
-    pub fn return_roles(&self) -> &Vec<roles::RoleTemplate> {
+    pub fn return_roles(&self) -> &Vec<u64> {
         &self.roles
     }
 
-    fn return_roles_mut(&mut self) -> &mut Vec<roles::RoleTemplate>{
-        &mut self.roles
+    pub fn add_role(&mut self, role_id: u64){
+        self.roles.push(role_id);
     }
 
-    pub fn add_role(&mut self, new_role: roles::RoleTemplate){
-        self.roles.push(new_role);
+    // Returns false if the user didn't have the role
+    pub fn remove_role(&mut self, role_id: u64) -> bool {
+        let len = self.roles.len();
+        self.roles.retain(|r| *r != role_id);
+        len != self.roles.len()
     }
 }
\ No newline at end of file
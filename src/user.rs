@@ -1,30 +1,83 @@
-use std::{cell::RefCell, fmt::Display, rc::Rc};
-use crate::place::Place;
+use std::{cell::RefCell, fmt::Display, rc::Rc, time::{Duration, SystemTime}};
+use crate::place::{Place, GENERAL_CHANNEL_ID};
+use crate::auth::TwoFactor;
 
 
 #[derive(Debug)]
 pub struct User{
-    name: String,
+    profile: Profile,
     id: u64,
     pub place: Rc<RefCell<Place>>,
+    // Channel inside the current place
+    pub channel: u64,
+    // Places the user is a member of, viewed or not, in the user's order
+    joined_places: Vec<JoinedPlace>,
+    // Chosen state, idle is worked out from last_seen
+    presence: Presence,
+    last_seen: Option<SystemTime>,
+    // System-wide, separate from place roles
+    operator: bool,
+    suspension: Option<Suspension>,
+    // Set for seeded passwords, cleared by set_password
+    must_change_password: bool,
+    two_factor: Option<TwoFactor>,
     pub data: UserData
 }
 
 impl User {
     pub fn new(name: String, pass: String, id: u64, place: Rc<RefCell<Place>>) -> User{
         User {
-            name: String::new(),
+            profile: Profile::new(),
             id,
             place,
+            channel: GENERAL_CHANNEL_ID,
+            joined_places: vec![],
+            presence: Presence::Offline,
+            last_seen: None,
+            operator: false,
+            suspension: None,
+            must_change_password: false,
+            two_factor: None,
             data: UserData::new(name, pass, String::new())
         }
     }
 
+    // Display name if set, login otherwise
     pub fn name(&self) -> String {
-        if !self.name.is_empty() {self.name.clone()}
+        if !self.profile.display_name.is_empty() {self.profile.display_name.clone()}
         else {self.data.login.clone()}
     }
 
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    // Uniqueness is checked by the Simulation, which knows the other users
+    pub fn set_display_name(&mut self, name: &str) -> Result<(), &'static str> {
+        let name = name.trim();
+        if !name.is_empty() {Profile::check_display_name(name)?;}
+        self.profile.display_name = name.to_string();
+        Ok(())
+    }
+
+    pub fn set_bio(&mut self, bio: &str) -> Result<(), &'static str> {
+        if bio.chars().count() > MAX_BIO_LENGTH {return Err("Bio is too long");}
+        self.profile.bio = bio.trim().to_string();
+        Ok(())
+    }
+
+    pub fn set_pronouns(&mut self, pronouns: &str) -> Result<(), &'static str> {
+        if pronouns.chars().count() > MAX_PRONOUNS_LENGTH {return Err("Pronouns are too long");}
+        self.profile.pronouns = pronouns.trim().to_string();
+        Ok(())
+    }
+
+    pub fn set_status(&mut self, status: &str) -> Result<(), &'static str> {
+        if status.chars().count() > MAX_STATUS_LENGTH {return Err("Status is too long");}
+        self.profile.status = status.trim().to_string();
+        Ok(())
+    }
+
     pub fn login(&self) -> String {
         self.data.login.clone()
     }
@@ -33,25 +86,318 @@ impl User {
         self.data.pass.clone()
     }
 
+    // Accounts without a password can't log in
+    pub fn has_password(&self) -> bool {
+        !self.data.pass.is_empty()
+    }
+
+    pub fn set_password(&mut self, pass: String) {
+        self.data.pass = pass;
+        self.must_change_password = false;
+    }
+
+    pub fn must_change_password(&self) -> bool {
+        self.must_change_password
+    }
+
+    pub fn require_password_change(&mut self) {
+        self.must_change_password = true;
+    }
+
     pub fn id(&self) -> u64 {
         self.id
     }
+
+    // Presence
+    pub fn presence(&self) -> Presence {
+        self.presence_at(SystemTime::now())
+    }
+
+    pub fn presence_at(&self, now: SystemTime) -> Presence {
+        match (self.presence, self.last_seen) {
+            (Presence::Online, Some(seen)) if now.duration_since(seen).unwrap_or_default() >= IDLE_AFTER => Presence::Idle,
+            (presence, _) => presence,
+        }
+    }
+
+    pub fn set_presence(&mut self, presence: Presence) {
+        self.presence = presence;
+    }
+
+    pub fn last_seen(&self) -> Option<SystemTime> {
+        self.last_seen
+    }
+
+    // Marks activity, which also ends idle
+    pub fn touch(&mut self) {
+        self.last_seen = Some(SystemTime::now());
+    }
+
+    // Two factor login, only asked for once enrollment is confirmed
+    pub fn two_factor(&self) -> Option<&TwoFactor> {
+        self.two_factor.as_ref()
+    }
+
+    pub fn two_factor_mut(&mut self) -> Option<&mut TwoFactor> {
+        self.two_factor.as_mut()
+    }
+
+    pub fn set_two_factor(&mut self, two_factor: Option<TwoFactor>) {
+        self.two_factor = two_factor;
+    }
+
+    pub fn requires_second_factor(&self) -> bool {
+        self.two_factor.as_ref().is_some_and(|t| t.confirmed)
+    }
+
+    // Operators and suspensions
+    pub fn is_operator(&self) -> bool {
+        self.operator
+    }
+
+    pub fn set_operator(&mut self, operator: bool) {
+        self.operator = operator;
+    }
+
+    // Expired suspensions don't count
+    pub fn suspension(&self) -> Option<&Suspension> {
+        self.suspension.as_ref().filter(|s| s.is_active())
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspension().is_some()
+    }
+
+    pub fn suspend(&mut self, suspension: Suspension) {
+        self.suspension = Some(suspension);
+    }
+
+    pub fn lift_suspension(&mut self) -> bool {
+        self.suspension.take().is_some_and(|s| s.is_active())
+    }
+
+    // Joined places
+    pub fn joined_places(&self) -> &Vec<JoinedPlace> {
+        &self.joined_places
+    }
+
+    pub fn has_joined(&self, place_id: u64) -> bool {
+        self.joined_places.iter().any(|p| p.place_id == place_id)
+    }
+
+    // New places go to the end of the list
+    pub fn add_joined_place(&mut self, place_id: u64) {
+        if self.has_joined(place_id) {return;}
+        self.joined_places.push(JoinedPlace::new(place_id));
+    }
+
+    pub fn remove_joined_place(&mut self, place_id: u64) {
+        self.joined_places.retain(|p| p.place_id != place_id);
+    }
+
+    pub fn mark_visited(&mut self, place_id: u64) {
+        if let Some(p) = self.joined_places.iter_mut().find(|p| p.place_id == place_id) {
+            p.last_visited = Some(SystemTime::now());
+        }
+    }
+
+    // Positions are counted from 1, like in the printed list
+    pub fn joined_place_at(&self, position: usize) -> Option<u64> {
+        if position == 0 {return None;}
+        self.joined_places.get(position - 1).map(|p| p.place_id)
+    }
+
+    pub fn set_favourite(&mut self, position: usize, favourite: bool) -> Result<(), &'static str> {
+        if position == 0 {return Err("There is no place at this position");}
+        let p = self.joined_places.get_mut(position - 1).ok_or("There is no place at this position")?;
+        p.favourite = favourite;
+        Ok(())
+    }
+
+    pub fn move_joined_place(&mut self, from: usize, to: usize) -> Result<(), &'static str> {
+        let len = self.joined_places.len();
+        if from == 0 || to == 0 || from > len || to > len {return Err("There is no place at this position");}
+        let p = self.joined_places.remove(from - 1);
+        self.joined_places.insert(to - 1, p);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FriendRequest {
+    pub from: u64,
+    pub to: u64,
+    pub time: SystemTime
+}
+
+impl FriendRequest {
+    pub fn new(from: u64, to: u64) -> FriendRequest {
+        FriendRequest {
+            from,
+            to,
+            time: SystemTime::now()
+        }
+    }
+}
+
+// Friend as seen in the friends list
+#[derive(Debug, Clone)]
+pub struct FriendInfo {
+    pub id: u64,
+    pub name: String,
+    pub presence: Presence,
+    // Name of the place the friend is viewing, if the viewer may know it
+    pub place: Option<String>
+}
+
+impl Display for FriendInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}) - {}", self.name, self.id, self.presence)?;
+        match &self.place {
+            Some(place) => write!(f, ", in {place}"),
+            None => write!(f, ", somewhere private"),
+        }
+    }
+}
+
+// Entry of the user's own place list
+#[derive(Debug, Clone)]
+pub struct JoinedPlace {
+    pub place_id: u64,
+    pub favourite: bool,
+    pub last_visited: Option<SystemTime>
+}
+
+impl JoinedPlace {
+    pub fn new(place_id: u64) -> JoinedPlace {
+        JoinedPlace {
+            place_id,
+            favourite: false,
+            last_visited: None
+        }
+    }
 }
 
 impl PartialEq for User {
     fn eq(&self, other: &Self) -> bool {
-        if self.id == other.id {
-            true
+        self.id == other.id
+    }
+}
+
+impl Display for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "User {}, login {} [{}]", self.name(), self.data.login, self.presence())?;
+        if !self.profile.status.is_empty() {
+            write!(f, " - {}", self.profile.status)?;
         }
-        else {
-            false
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Suspension {
+    pub reason: String,
+    // Id of the operator
+    pub by: u64,
+    pub since: SystemTime,
+    // None suspends until lifted
+    pub until: Option<SystemTime>
+}
+
+impl Suspension {
+    pub fn new(reason: String, by: u64, duration: Option<Duration>) -> Suspension {
+        let since = SystemTime::now();
+        Suspension {
+            reason,
+            by,
+            since,
+            until: duration.map(|d| since + d)
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        match self.until {
+            Some(until) => SystemTime::now() < until,
+            None => true,
         }
     }
 }
 
-impl Display for User {
+impl Display for Suspension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.until {
+            Some(until) => write!(f, "Suspended until {}: {}", chrono::DateTime::<chrono::Local>::from(until).format("%Y-%m-%d %H:%M"), self.reason),
+            None => write!(f, "Suspended indefinitely: {}", self.reason),
+        }
+    }
+}
+
+// Online users go idle after this long without activity
+pub const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Presence {
+    Online,
+    Idle,
+    DoNotDisturb,
+    Offline
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Idle => write!(f, "idle"),
+            Presence::DoNotDisturb => write!(f, "do not disturb"),
+            Presence::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileField {
+    DisplayName,
+    Bio,
+    Pronouns,
+    Status
+}
+
+pub const MIN_DISPLAY_NAME_LENGTH: usize = 2;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+pub const MAX_BIO_LENGTH: usize = 300;
+pub const MAX_PRONOUNS_LENGTH: usize = 20;
+pub const MAX_STATUS_LENGTH: usize = 80;
+
+// Public part of the user, empty fields are not set
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub display_name: String,
+    pub bio: String,
+    pub pronouns: String,
+    pub status: String
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    pub fn check_display_name(name: &str) -> Result<(), &'static str> {
+        let len = name.chars().count();
+        if len < MIN_DISPLAY_NAME_LENGTH {return Err("Display name is too short");}
+        if len > MAX_DISPLAY_NAME_LENGTH {return Err("Display name is too long");}
+        if name.chars().any(|c| c.is_control()) {return Err("Display name cannot contain control characters");}
+        // Numbers are read as user ids when looking someone up
+        if name.chars().all(|c| c.is_ascii_digit()) {return Err("Display name cannot be only digits");}
+        Ok(())
+    }
+}
+
+impl Display for Profile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "User {}, login {}", self.name, self.data.login)
+        let or_none = |s: &String| if s.is_empty() {"-".to_string()} else {s.clone()};
+        write!(f, "Display name: {}\nPronouns: {}\nStatus: {}\nBio: {}",
+            or_none(&self.display_name), or_none(&self.pronouns), or_none(&self.status), or_none(&self.bio))
     }
 }
 
@@ -60,7 +406,11 @@ pub struct UserData {
     pass: String,
     login: String,
     pub email: String,
-    pub friends: Vec<Rc<RefCell<User>>>
+    pub email_verified: bool,
+    // Ids, so friends don't keep each other alive
+    friends: Vec<u64>,
+    // Users blocked in every place
+    blocked: Vec<u64>
 }
 
 impl UserData {
@@ -69,8 +419,50 @@ impl UserData {
             pass,
             login,
             email,
-            friends: vec![]
+            email_verified: false,
+            friends: vec![],
+            blocked: vec![]
         }
     }
 
+    pub fn friends(&self) -> &Vec<u64> {
+        &self.friends
+    }
+
+    pub fn is_friend(&self, user_id: u64) -> bool {
+        self.friends.contains(&user_id)
+    }
+
+    pub fn add_friend(&mut self, user_id: u64) {
+        if !self.is_friend(user_id) {
+            self.friends.push(user_id);
+        }
+    }
+
+    pub fn remove_friend(&mut self, user_id: u64) -> bool {
+        let len = self.friends.len();
+        self.friends.retain(|id| *id != user_id);
+        len != self.friends.len()
+    }
+
+    pub fn blocked(&self) -> &Vec<u64> {
+        &self.blocked
+    }
+
+    pub fn has_blocked(&self, user_id: u64) -> bool {
+        self.blocked.contains(&user_id)
+    }
+
+    pub fn block(&mut self, user_id: u64) {
+        if !self.has_blocked(user_id) {
+            self.blocked.push(user_id);
+        }
+    }
+
+    pub fn unblock(&mut self, user_id: u64) -> bool {
+        let len = self.blocked.len();
+        self.blocked.retain(|id| *id != user_id);
+        len != self.blocked.len()
+    }
+
 }
\ No newline at end of file